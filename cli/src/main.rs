@@ -16,14 +16,18 @@
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use std::{fs::File, io::Read};
+mod session;
 
-use clap::Parser;
+use std::{error::Error, fs::File, io::Read};
+
+use clap::{Parser, Subcommand, ValueEnum};
 use lib::{
-    chrono::{Local, NaiveDateTime},
-    Account,
+    chrono::{NaiveDateTime, Utc},
+    chrono_tz::Tz,
+    verify_payload, Account, Crs, Endpoint, MileageCheck, MileageMode, RetryPolicy, Route,
+    RouteInfo, RouteSelector, RoutineLine, RunType, Track, UploadReceipt, UploadRequest,
 };
-use log::{debug, info, Level, Metadata, Record};
+use log::{debug, info, warn, Level, Metadata, Record};
 
 struct SimpleLogger {
     level: Level,
@@ -44,28 +48,386 @@ impl log::Log for SimpleLogger {
 }
 
 #[derive(Parser)]
-#[command(version, about)]
+#[command(
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
-    #[arg(short, long)]
-    username: String,
-    #[arg(short, long)]
-    password: String,
-    #[arg(short, long)]
-    mileage: f64,
-    #[arg(short, long)]
-    route: String,
-
-    /// Time of the run, in the format "YYYY-MM-DD HH:MM:SS"
-    #[arg(short, long)]
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[arg(short, long, required = true)]
+    username: Option<String>,
+    #[arg(short, long, required = true)]
+    password: Option<String>,
+    #[arg(short, long, required_unless_present = "track")]
+    mileage: Option<f64>,
+    /// Route file in GeoJSON, KML or KMZ format
+    #[arg(short, long, required_unless_present = "track")]
+    route: Option<String>,
+
+    /// Route to use if the file has several, by index or by name
+    #[arg(short, long, value_parser = parse_selector, conflicts_with = "track")]
+    select: Option<RouteSelector>,
+
+    /// Coordinate system of the route file, overriding the one it declares: wgs84, gcj02 or bd09
+    #[arg(long, value_parser = parse_crs, conflicts_with = "track")]
+    crs: Option<Crs>,
+
+    /// End time of the run in the server's time zone, in the format "YYYY-MM-DD HH:MM:SS"
+    #[arg(short, long, conflicts_with = "track")]
     time: Option<String>,
 
+    /// Time zone the server reads run times in
+    #[arg(long, env = "PRETTY_DER6Y_TIMEZONE", value_parser = parse_timezone, default_value = "Asia/Shanghai")]
+    timezone: Tz,
+
+    /// Number of parts the run along the route is split into by pauses
+    #[arg(long, default_value_t = 1, conflicts_with = "track")]
+    segments: u8,
+
+    /// Seed for the generated line and timing, to reproduce a payload
+    #[arg(long, conflicts_with = "track")]
+    seed: Option<u64>,
+
+    /// Kind of run: free or checkpoint, defaulting to checkpoint if the route marks any
+    #[arg(long, value_parser = parse_run_type, conflicts_with = "track")]
+    run_type: Option<RunType>,
+
+    /// Upload a recorded GPX or FIT track with its own distance and timing
+    #[arg(long, conflicts_with_all = ["mileage", "route"])]
+    track: Option<String>,
+
+    /// Base URL of the API server
+    #[arg(long, env = "PRETTY_DER6Y_BASE_URL")]
+    base_url: Option<String>,
+
+    /// Override the URL of a single endpoint, in the format "NAME=URL"
+    #[arg(long, value_parser = parse_endpoint)]
+    endpoint: Vec<(Endpoint, String)>,
+
+    /// Attempts per request, including the first one
+    #[arg(long, default_value_t = RetryPolicy::default().max_attempts)]
+    attempts: u32,
+
+    /// Retry the upload as well, which may record the run twice
+    #[arg(long)]
+    retry_upload: bool,
+
+    /// Refuse to upload a mileage beyond the running limits instead of capping it
+    #[arg(long)]
+    strict: bool,
+
+    /// Print the signed payload instead of uploading it
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Log in again instead of reusing the cached session
+    #[arg(long)]
+    no_session: bool,
+
     /// Verbosity level
     #[arg(short, action = clap::ArgAction::Count)]
     verbose: u8,
 }
 
+#[derive(Subcommand)]
+enum Command {
+    /// Inspect route files without logging in
+    #[command(subcommand)]
+    Route(RouteCommand),
+
+    /// Check a saved upload payload against its own signatures
+    Inspect {
+        /// Payload file, as printed by --dry-run
+        file: String,
+
+        /// User the payload was signed for
+        #[arg(long, requires = "school_id", required_unless_present = "username")]
+        user_id: Option<String>,
+
+        /// School the payload was signed for
+        #[arg(long, requires = "user_id")]
+        school_id: Option<String>,
+
+        /// Take the user and school from the cached session of this account
+        #[arg(short, long, conflicts_with = "user_id")]
+        username: Option<String>,
+
+        /// Time zone the server reads run times in
+        #[arg(long, env = "PRETTY_DER6Y_TIMEZONE", value_parser = parse_timezone, default_value = "Asia/Shanghai")]
+        timezone: Tz,
+    },
+}
+
+#[derive(Subcommand)]
+enum RouteCommand {
+    /// Print the length, extent and possible problems of a route
+    Info {
+        /// Route file in GeoJSON, KML or KMZ format
+        file: String,
+
+        /// Route to use if the file has several, by index or by name
+        #[arg(short, long, value_parser = parse_selector)]
+        select: Option<RouteSelector>,
+
+        /// Coordinate system of the route file, overriding the one it declares: wgs84, gcj02 or bd09
+        #[arg(long, value_parser = parse_crs)]
+        crs: Option<Crs>,
+    },
+
+    /// Write the line a run along a route would send, to check it in a map viewer
+    Export {
+        /// Route file in GeoJSON, KML or KMZ format, or a payload with --payload
+        file: String,
+
+        /// Distance to generate along the route, in kilometers
+        #[arg(short, long, required_unless_present = "payload")]
+        mileage: Option<f64>,
+
+        /// Number of parts the run is split into by pauses
+        #[arg(long, default_value_t = 1, conflicts_with = "payload")]
+        segments: u8,
+
+        /// Seed for the generated line, to reproduce it
+        #[arg(long, conflicts_with = "payload")]
+        seed: Option<u64>,
+
+        /// Route to use if the file has several, by index or by name
+        #[arg(short, long, value_parser = parse_selector, conflicts_with = "payload")]
+        select: Option<RouteSelector>,
+
+        /// Coordinate system of the route file, overriding the one it declares: wgs84, gcj02 or bd09
+        #[arg(long, value_parser = parse_crs, conflicts_with = "payload")]
+        crs: Option<Crs>,
+
+        /// Read the line of a payload printed by --dry-run instead of generating one
+        #[arg(long)]
+        payload: bool,
+
+        #[arg(short, long, value_enum, default_value_t = ExportFormat::Geojson)]
+        format: ExportFormat,
+
+        /// Convert the line back to WGS-84 instead of keeping the GCJ-02 sent to the server
+        #[arg(long)]
+        wgs84: bool,
+
+        /// File to write to instead of the standard output
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ExportFormat {
+    Geojson,
+    Gpx,
+}
+
+fn parse_endpoint(s: &str) -> Result<(Endpoint, String), String> {
+    let (name, url) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Expected NAME=URL, got {}", s))?;
+    let endpoint = name.parse::<Endpoint>().map_err(|e| e.to_string())?;
+    Ok((endpoint, url.to_string()))
+}
+
+fn parse_timezone(s: &str) -> Result<Tz, String> {
+    s.parse::<Tz>().map_err(|e| e.to_string())
+}
+
+fn read_file(path: &str) -> std::io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+    Ok(content)
+}
+
+fn parse_selector(s: &str) -> Result<RouteSelector, String> {
+    Ok(match s.parse() {
+        Ok(index) => RouteSelector::Index(index),
+        Err(_) => RouteSelector::Name(s.to_string()),
+    })
+}
+
+fn parse_crs(s: &str) -> Result<Crs, String> {
+    s.parse().map_err(|e: lib::Error| e.to_string())
+}
+
+fn parse_run_type(s: &str) -> Result<RunType, String> {
+    s.parse().map_err(|e: lib::Error| e.to_string())
+}
+
+fn load_route(
+    path: &str,
+    selector: Option<&RouteSelector>,
+    crs: Option<Crs>,
+) -> Result<Route, Box<dyn Error>> {
+    let extension = path.rsplit('.').next().unwrap_or_default().to_lowercase();
+    let route = match extension.as_str() {
+        "kml" => Route::from_kml(&read_file(path)?, selector)?,
+        "kmz" => Route::from_kmz(&std::fs::read(path)?, selector)?,
+        _ => Route::from_geojson(&read_file(path)?, selector)?,
+    };
+    Ok(match crs {
+        Some(crs) => route.with_crs(crs),
+        None => route,
+    })
+}
+
+fn print_route_info(info: &RouteInfo) {
+    let bbox = &info.bounding_box;
+    println!("Length:         {:.3} km", info.length);
+    println!("Points:         {}", info.points);
+    println!(
+        "Bounding box:   {:.6}, {:.6} - {:.6}, {:.6}",
+        bbox.min_longitude, bbox.min_latitude, bbox.max_longitude, bbox.max_latitude
+    );
+    println!("Max segment:    {:.1} m", info.max_segment);
+    println!("Closure gap:    {:.1} m", info.closure_gap);
+    println!("Checkpoints:    {}", info.checkpoints);
+    println!("Coordinates:    {}", info.crs);
+    println!(
+        "Inside China:   {}",
+        if info.in_china { "yes" } else { "no" }
+    );
+
+    if !info.duplicate_points.is_empty() {
+        let indices: Vec<_> = info.duplicate_points.iter().map(usize::to_string).collect();
+        println!("Duplicates at:  {}", indices.join(", "));
+    }
+    for warning in &info.warnings {
+        println!("Warning: {}", warning);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn export_route(
+    file: &str,
+    mileage: Option<f64>,
+    segments: u8,
+    seed: Option<u64>,
+    select: Option<&RouteSelector>,
+    crs: Option<Crs>,
+    payload: bool,
+    format: ExportFormat,
+    wgs84: bool,
+    output: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let line = if payload {
+        RoutineLine::from_payload(&read_file(file)?)?
+    } else {
+        // Required by clap unless a payload is given.
+        let mileage = mileage.unwrap();
+        let route = load_route(file, select, crs)?;
+        match seed {
+            Some(seed) => route.routine_seeded(mileage, segments, seed)?,
+            None => route.routine(mileage, segments)?,
+        }
+    };
+    let crs = if wgs84 { Crs::Wgs84 } else { Crs::Gcj02 };
+    let content = match format {
+        ExportFormat::Geojson => line.to_geojson(crs)?,
+        ExportFormat::Gpx => line.to_gpx(crs),
+    };
+    match output {
+        Some(path) => {
+            std::fs::write(path, content)?;
+            info!(
+                "Wrote {:.3} km in {} segment(s) to {}",
+                line.length(),
+                line.segment_count(),
+                path
+            );
+        }
+        None => println!("{}", content),
+    }
+    Ok(())
+}
+
+fn inspect(
+    file: &str,
+    ids: Option<(String, String)>,
+    username: Option<String>,
+    timezone: Tz,
+) -> Result<(), Box<dyn Error>> {
+    let (user_id, school_id) = match (ids, username) {
+        (Some(ids), _) => ids,
+        (None, Some(username)) => {
+            let session = session::load(&username)
+                .ok_or_else(|| format!("No cached session for {}", username))?;
+            (
+                session.user_id().to_string(),
+                session.school_id().to_string(),
+            )
+        }
+        // One of them is required by clap.
+        (None, None) => unreachable!(),
+    };
+
+    let mismatches = verify_payload(&read_file(file)?, &user_id, &school_id, timezone)?;
+    if mismatches.is_empty() {
+        println!("oct, signDigital and signTime match the payload");
+        return Ok(());
+    }
+
+    for mismatch in &mismatches {
+        println!(
+            "{}: signed {}, found {}",
+            mismatch.field, mismatch.signed, mismatch.found
+        );
+    }
+    Err(format!("{} fields do not match their signature", mismatches.len()).into())
+}
+
+fn report_mileage(check: &MileageCheck) {
+    if !check.is_clamped() {
+        if check.within_range {
+            info!("{:.2} km fits the running limits", check.requested);
+        }
+        return;
+    }
+
+    let mut reasons = Vec::new();
+    if !check.within_daily {
+        reasons.push("daily cap");
+    }
+    if !check.within_weekly {
+        reasons.push("weekly cap");
+    }
+    if !check.within_range {
+        reasons.push("longest run that counts");
+    }
+    warn!(
+        "{:.2} km exceeds the {}, only {:.2} km will count",
+        check.requested,
+        reasons.join(" and the "),
+        check.adjusted
+    );
+}
+
+fn print_receipt(receipt: &UploadReceipt) {
+    if let Some(id) = &receipt.record_id {
+        println!("Record:         {}", id);
+    }
+    if let Some(mileage) = receipt.effective_mileage {
+        println!("Effective:      {:.2} km", mileage);
+    }
+    if !receipt.is_effective() {
+        warn!(
+            "The run does not count: {}",
+            receipt
+                .uneffective_reason
+                .as_deref()
+                .unwrap_or("no reason given")
+        );
+    }
+}
+
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
 
     let logger = SimpleLogger {
@@ -80,32 +442,146 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     log::set_boxed_logger(Box::new(logger)).map(|()| log::set_max_level(level_filter))?;
 
-    let mut account = Account::new();
+    match args.command {
+        Some(Command::Route(RouteCommand::Info { file, select, crs })) => {
+            print_route_info(&load_route(&file, select.as_ref(), crs)?.info());
+            return Ok(());
+        }
+        Some(Command::Route(RouteCommand::Export {
+            file,
+            mileage,
+            segments,
+            seed,
+            select,
+            crs,
+            payload,
+            format,
+            wgs84,
+            output,
+        })) => {
+            return export_route(
+                &file,
+                mileage,
+                segments,
+                seed,
+                select.as_ref(),
+                crs,
+                payload,
+                format,
+                wgs84,
+                output.as_deref(),
+            )
+        }
+        Some(Command::Inspect {
+            file,
+            user_id,
+            school_id,
+            username,
+            timezone,
+        }) => return inspect(&file, user_id.zip(school_id), username, timezone),
+        None => {}
+    }
+
+    // Both are required by clap unless a subcommand is given.
+    let (Some(username), Some(password)) = (args.username, args.password) else {
+        unreachable!()
+    };
+
+    let mut builder = Account::builder()
+        .retry_policy(RetryPolicy {
+            max_attempts: args.attempts,
+            retry_upload: args.retry_upload,
+            ..Default::default()
+        })
+        .mileage_mode(if args.strict {
+            MileageMode::Strict
+        } else {
+            MileageMode::Clamp
+        })
+        .timezone(args.timezone);
+    if let Some(base_url) = args.base_url {
+        builder = builder.base_url(base_url);
+    }
+    for (endpoint, url) in args.endpoint {
+        builder = builder.endpoint(endpoint, url);
+    }
+    let mut account = builder.build()?;
 
     info!("Logging in");
-    debug!("Username: {}", args.username);
-    debug!("Password: {}", args.password);
+    debug!("Username: {}", username);
+    debug!("Password: {}", password);
+
+    match session::load(&username).filter(|_| !args.no_session) {
+        Some(session) => account.resume(session, &username, &password).await?,
+        None => account.login(&username, &password).await?,
+    }
+
+    if let Some(session) = account.export_session() {
+        session::save(&username, &session);
+    }
+
+    if let Some(path) = args.track {
+        let track = if path.to_lowercase().ends_with(".fit") {
+            Track::from_fit(&std::fs::read(&path)?)?
+        } else {
+            Track::from_gpx(&read_file(&path)?)?
+        };
+
+        debug!("Distance: {}", track.distance());
+        debug!("Start time: {}", track.start_time());
+        debug!("End time: {}", track.end_time());
+
+        report_mileage(&account.check_mileage(track.distance()));
+
+        let request = UploadRequest::track(track);
+        if args.dry_run {
+            println!("{:#}", account.preview(&request)?);
+        } else {
+            info!("Uploading recorded track");
+            print_receipt(&account.upload(&request).await?);
+        }
+        return Ok(());
+    }
 
-    account.login(&args.username, &args.password).await?;
+    // Both are required by clap unless a track is given.
+    let (Some(mileage), Some(route)) = (args.mileage, args.route) else {
+        unreachable!()
+    };
 
     let time = match args.time {
-        Some(time) => NaiveDateTime::parse_from_str(&time, "%Y-%m-%d %H:%M:%S")
-            .map(|t| t.and_local_timezone(Local).earliest().unwrap())?,
-        None => Local::now(),
+        Some(time) => NaiveDateTime::parse_from_str(&time, "%Y-%m-%d %H:%M:%S")?
+            .and_local_timezone(args.timezone)
+            .earliest()
+            .ok_or_else(|| format!("{} does not exist in {}", time, args.timezone))?,
+        None => Utc::now().with_timezone(&args.timezone),
     };
 
-    let mut file = File::open(&args.route)?;
-    let mut geojson = String::new();
-    file.read_to_string(&mut geojson)?;
+    let route = load_route(&route, args.select.as_ref(), args.crs)?;
 
-    info!("Uploading running data");
-    debug!("Route: {}", geojson);
-    debug!("Mileage: {}", args.mileage);
+    debug!("Route: {:?}", route);
+    debug!("Mileage: {}", mileage);
     debug!("Time: {}", time);
 
-    account
-        .upload_running(&geojson, args.mileage, &time)
-        .await?;
+    report_mileage(&account.check_mileage(mileage));
+
+    let mut request = UploadRequest::builder()
+        .route(route)
+        .distance(mileage)
+        .end_time(time)
+        .segments(args.segments);
+    if let Some(seed) = args.seed {
+        request = request.seed(seed);
+    }
+    if let Some(run_type) = args.run_type {
+        request = request.run_type(run_type);
+    }
+    let request = request.build()?;
+    if args.dry_run {
+        println!("{:#}", account.preview(&request)?);
+    } else {
+        info!("Uploading running data");
+        print_receipt(&account.upload(&request).await?);
+    }
 
     Ok(())
 }
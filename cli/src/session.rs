@@ -0,0 +1,76 @@
+/*
+    Pretty Der6y - A third-party running data upload client.
+    Copyright (C) 2024  Fay Ash
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published
+    by the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::path::PathBuf;
+
+use lib::Session;
+use log::{debug, warn};
+
+/// Escapes every character of `username` that may not be safe in a file
+/// name, so it cannot point outside the sessions directory.
+fn file_name(username: &str) -> String {
+    let mut name = String::new();
+    for byte in username.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => name.push(byte as char),
+            _ => name += &format!("%{:02X}", byte),
+        }
+    }
+    name + ".json"
+}
+
+fn path(username: &str) -> Option<PathBuf> {
+    Some(
+        dirs::config_dir()?
+            .join("pretty-der6y")
+            .join("sessions")
+            .join(file_name(username)),
+    )
+}
+
+/// Loads the cached session of `username`, if there is a readable one.
+pub fn load(username: &str) -> Option<Session> {
+    let path = path(username)?;
+    if !path.exists() {
+        return None;
+    }
+
+    match Session::load(&path) {
+        Ok(session) => {
+            debug!("Loaded session from {}", path.display());
+            Some(session)
+        }
+        Err(e) => {
+            warn!("Ignoring invalid session cache {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Caches the session of `username`, logging instead of failing on errors.
+pub fn save(username: &str, session: &Session) {
+    let Some(path) = path(username) else {
+        warn!("No config directory to cache the session in");
+        return;
+    };
+
+    match session.save(&path) {
+        Ok(()) => debug!("Saved session to {}", path.display()),
+        Err(e) => warn!("Failed to cache the session: {}", e),
+    }
+}
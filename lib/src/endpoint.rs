@@ -0,0 +1,151 @@
+/*
+    Pretty Der6y - A third-party running data upload client.
+    Copyright (C) 2024  Fay Ash
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published
+    by the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{fmt, str::FromStr};
+
+use reqwest::{header::HeaderValue, Url};
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Result};
+
+/// An API endpoint requested by [`Account`](crate::Account).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Endpoint {
+    Login,
+    Current,
+    Version,
+    RunningLimit,
+    Upload,
+}
+
+impl Endpoint {
+    pub const ALL: [Endpoint; 5] = [
+        Endpoint::Login,
+        Endpoint::Current,
+        Endpoint::Version,
+        Endpoint::RunningLimit,
+        Endpoint::Upload,
+    ];
+
+    /// The path of the endpoint, relative to the base URL.
+    pub fn path(self) -> &'static str {
+        match self {
+            Endpoint::Login => "/authorization/user/v2/manage/login",
+            Endpoint::Current => "/education/semester/getCurrent",
+            Endpoint::Version => "/authorization/mobileApp/getLastVersion?platform=2",
+            Endpoint::RunningLimit => "/running/app/getRunningLimit",
+            Endpoint::Upload => "/running//app/v3/upload",
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Endpoint::Login => "login",
+            Endpoint::Current => "current",
+            Endpoint::Version => "version",
+            Endpoint::RunningLimit => "running-limit",
+            Endpoint::Upload => "upload",
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Endpoint::ALL
+            .into_iter()
+            .find(|endpoint| endpoint.name() == s)
+            .ok_or_else(|| Error::Config(format!("Unknown endpoint: {}", s)))
+    }
+}
+
+/// A resolved endpoint URL together with the `HOST` header sent to it.
+#[derive(Clone, Debug)]
+pub(crate) struct Target {
+    pub(crate) url: Url,
+    pub(crate) host: HeaderValue,
+}
+
+impl Target {
+    pub(crate) fn parse(url: &str) -> Result<Self> {
+        let url = Url::parse(url).map_err(|e| Error::Config(format!("{}: {}", url, e)))?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| Error::Config(format!("{}: missing host", url)))?;
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+
+        Ok(Self {
+            host: host.parse()?,
+            url,
+        })
+    }
+
+    pub(crate) fn join(base_url: &str, endpoint: Endpoint) -> Result<Self> {
+        Self::parse(&format!(
+            "{}{}",
+            base_url.trim_end_matches('/'),
+            endpoint.path()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_endpoint_names() {
+        for endpoint in Endpoint::ALL {
+            assert_eq!(endpoint.to_string().parse::<Endpoint>().unwrap(), endpoint);
+        }
+        assert!("unknown".parse::<Endpoint>().is_err());
+    }
+
+    #[test]
+    fn test_target_join() {
+        let target = Target::join("http://127.0.0.1:8080/proxy/", Endpoint::Upload).unwrap();
+        assert_eq!(
+            target.url.as_str(),
+            "http://127.0.0.1:8080/proxy/running//app/v3/upload"
+        );
+        assert_eq!(target.host, "127.0.0.1:8080");
+
+        let target = Target::join("https://example.com", Endpoint::Version).unwrap();
+        assert_eq!(
+            target.url.as_str(),
+            "https://example.com/authorization/mobileApp/getLastVersion?platform=2"
+        );
+        assert_eq!(target.host, "example.com");
+    }
+
+    #[test]
+    fn test_target_invalid() {
+        assert!(matches!(Target::parse("not a url"), Err(Error::Config(_))));
+    }
+}
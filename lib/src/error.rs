@@ -0,0 +1,160 @@
+/*
+    Pretty Der6y - A third-party running data upload client.
+    Copyright (C) 2024  Fay Ash
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published
+    by the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use reqwest::StatusCode;
+
+/// Errors returned by [`Account`](crate::Account) and the route and security helpers.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// The server rejected the username or password.
+    #[error("Invalid account or password")]
+    Auth,
+
+    /// The server no longer accepts the access token.
+    #[error("Access token rejected by the server")]
+    Unauthorized,
+
+    /// The token expired and there are no credentials to log in again with.
+    #[error("Session expired, please log in again")]
+    ReloginRequired,
+
+    /// The request could not be sent or its response could not be read.
+    #[error("Network error: {0}")]
+    Network(#[from] reqwest::Error),
+
+    /// The server answered with a non-success status code.
+    #[error("Server responded with {0}")]
+    Status(StatusCode),
+
+    /// A response, payload or timestamp could not be decoded.
+    #[error("Decode error: {0}")]
+    Decode(String),
+
+    /// Encryption, decryption or key derivation failed.
+    #[error("Crypto error: {0}")]
+    Crypto(String),
+
+    /// The route file is malformed or cannot be used.
+    #[error("Route error: {0}")]
+    Route(String),
+
+    /// The route is too short to generate a run along it, with the length
+    /// of a lap in meters.
+    #[error("Route is too short to run along: {0:.2} m per lap")]
+    DegenerateRoute(f64),
+
+    /// The line along the route would need more points than allowed, with
+    /// the limit.
+    #[error("Route is too long to run along: the line would exceed {0} points")]
+    TooManyPoints(usize),
+
+    /// The semester or mileage limits do not allow the request.
+    #[error("{0}")]
+    Limit(String),
+
+    /// The upload request is incomplete or contradicts itself.
+    #[error("Invalid upload request: {0}")]
+    Request(String),
+
+    /// The account was configured with an invalid endpoint.
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+
+    /// A file could not be read or written.
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl Error {
+    /// A short, stable identifier of the error kind.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Auth => "auth",
+            Error::Unauthorized => "unauthorized",
+            Error::ReloginRequired => "relogin_required",
+            Error::Network(_) => "network",
+            Error::Status(_) => "status",
+            Error::Decode(_) => "decode",
+            Error::Crypto(_) => "crypto",
+            Error::Route(_) => "route",
+            Error::DegenerateRoute(_) => "degenerate_route",
+            Error::TooManyPoints(_) => "too_many_points",
+            Error::Limit(_) => "limit",
+            Error::Request(_) => "request",
+            Error::Config(_) => "config",
+            Error::Io(_) => "io",
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Decode(e.to_string())
+    }
+}
+
+impl From<reqwest::header::InvalidHeaderValue> for Error {
+    fn from(e: reqwest::header::InvalidHeaderValue) -> Self {
+        Error::Decode(e.to_string())
+    }
+}
+
+impl From<std::num::ParseFloatError> for Error {
+    fn from(e: std::num::ParseFloatError) -> Self {
+        Error::Decode(e.to_string())
+    }
+}
+
+impl From<chrono::ParseError> for Error {
+    fn from(e: chrono::ParseError) -> Self {
+        Error::Decode(e.to_string())
+    }
+}
+
+impl From<regex::Error> for Error {
+    fn from(e: regex::Error) -> Self {
+        Error::Decode(e.to_string())
+    }
+}
+
+impl From<quick_xml::Error> for Error {
+    fn from(e: quick_xml::Error) -> Self {
+        Error::Route(e.to_string())
+    }
+}
+
+impl From<quick_xml::events::attributes::AttrError> for Error {
+    fn from(e: quick_xml::events::attributes::AttrError) -> Self {
+        Error::Route(e.to_string())
+    }
+}
+
+impl From<zip::result::ZipError> for Error {
+    fn from(e: zip::result::ZipError) -> Self {
+        Error::Route(e.to_string())
+    }
+}
+
+impl From<geojson::Error> for Error {
+    fn from(e: geojson::Error) -> Self {
+        Error::Route(e.to_string())
+    }
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
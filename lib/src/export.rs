@@ -0,0 +1,196 @@
+/*
+    Pretty Der6y - A third-party running data upload client.
+    Copyright (C) 2024  Fay Ash
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published
+    by the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use geo::{prelude::*, Point};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{routine::LGPoint, Crs, Error, Result};
+
+/// A generated line, as sent to the server in GCJ-02, split into the
+/// segments between pauses.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RoutineLine {
+    segments: Vec<Vec<LGPoint>>,
+}
+
+impl RoutineLine {
+    pub(crate) fn new(segments: Vec<Vec<LGPoint>>) -> Self {
+        Self { segments }
+    }
+
+    pub(crate) fn into_segments(self) -> Vec<Vec<LGPoint>> {
+        self.segments
+    }
+
+    /// Reads the `routineLine` of a signed payload, as printed by a dry run.
+    ///
+    /// The payload does not record pauses, so the line has a single segment.
+    pub fn from_payload(payload: &str) -> Result<Self> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Payload {
+            routine_line: Vec<LGPoint>,
+        }
+
+        let line = serde_json::from_str::<Payload>(payload)?.routine_line;
+        if line.is_empty() {
+            return Err(Error::Route("Payload has no routine line".to_string()));
+        }
+        Ok(Self::new(vec![line]))
+    }
+
+    /// Number of segments between pauses.
+    pub fn segment_count(&self) -> usize {
+        self.segments.len()
+    }
+
+    /// Length of all segments in kilometers.
+    pub fn length(&self) -> f64 {
+        self.segments
+            .iter()
+            .flat_map(|segment| segment.windows(2))
+            .map(|pair| {
+                let a = Point::new(pair[0].longitude, pair[0].latitude);
+                let b = Point::new(pair[1].longitude, pair[1].latitude);
+                a.geodesic_distance(&b)
+            })
+            .sum::<f64>()
+            / 1000.
+    }
+
+    /// Latitude and longitude of every point, converted from GCJ-02.
+    fn positions(&self, crs: Crs) -> Vec<Vec<(f64, f64)>> {
+        self.segments
+            .iter()
+            .map(|segment| {
+                segment
+                    .iter()
+                    .map(|point| crs.convert_gcj02(point.latitude, point.longitude))
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Writes the line as a GeoJSON `Feature`, a `LineString` or a
+    /// `MultiLineString` if it has several segments.
+    ///
+    /// The coordinate system is declared in a `crs` property, which
+    /// [`Route::from_geojson`](crate::Route::from_geojson) reads back.
+    pub fn to_geojson(&self, crs: Crs) -> Result<String> {
+        let lines: Vec<Vec<[f64; 2]>> = self
+            .positions(crs)
+            .into_iter()
+            .map(|segment| segment.into_iter().map(|(lat, lon)| [lon, lat]).collect())
+            .collect();
+
+        let geometry = match lines.as_slice() {
+            [line] => json!({ "type": "LineString", "coordinates": line }),
+            _ => json!({ "type": "MultiLineString", "coordinates": lines }),
+        };
+
+        Ok(serde_json::to_string_pretty(&json!({
+            "type": "Feature",
+            "properties": { "crs": crs },
+            "geometry": geometry,
+        }))?)
+    }
+
+    /// Writes the line as a GPX 1.1 track, one `<trkseg>` per segment.
+    ///
+    /// GPX viewers expect WGS-84, other systems are noted in the track's
+    /// `<desc>`.
+    pub fn to_gpx(&self, crs: Crs) -> String {
+        let mut gpx = String::from(concat!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+            "\n",
+            r#"<gpx version="1.1" creator="pretty-der6y" xmlns="http://www.topografix.com/GPX/1/1">"#,
+            "\n  <trk>\n",
+        ));
+        if crs != Crs::Wgs84 {
+            gpx += &format!("    <desc>Coordinates in {}</desc>\n", crs);
+        }
+        for segment in self.positions(crs) {
+            gpx += "    <trkseg>\n";
+            for (lat, lon) in segment {
+                gpx += &format!("      <trkpt lat=\"{}\" lon=\"{}\"/>\n", lat, lon);
+            }
+            gpx += "    </trkseg>\n";
+        }
+        gpx += "  </trk>\n</gpx>\n";
+        gpx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Route;
+
+    fn line() -> RoutineLine {
+        let route = Route::from_geojson(
+            r#"{"type": "LineString", "crs": "gcj02", "coordinates": [[114.3, 30.5], [114.31, 30.5], [114.31, 30.51], [114.3, 30.51], [114.3, 30.5]]}"#,
+            None,
+        )
+        .unwrap();
+        route.routine(2.0, 2).unwrap()
+    }
+
+    #[test]
+    fn test_to_geojson() {
+        let line = line();
+        assert_eq!(line.segment_count(), 2);
+
+        let geojson = line.to_geojson(Crs::Gcj02).unwrap();
+        let route = Route::from_geojson(&geojson, None).unwrap();
+        assert_eq!(route.crs(), Crs::Gcj02);
+        // Route info measures in WGS-84, a few meters off over kilometers,
+        // and closes the lap the line leaves open.
+        let info = route.info();
+        assert!((info.length - info.closure_gap / 1000. - line.length()).abs() < 1e-2);
+
+        let wgs84 = Route::from_geojson(&line.to_geojson(Crs::Wgs84).unwrap(), None).unwrap();
+        assert_eq!(wgs84.crs(), Crs::Wgs84);
+        assert!((wgs84.info().length - route.info().length).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_to_gpx() {
+        let line = line();
+        let gpx = line.to_gpx(Crs::Wgs84);
+        assert_eq!(gpx.matches("<trkseg>").count(), 2);
+        assert_eq!(
+            gpx.matches("<trkpt ").count(),
+            line.segments.iter().map(Vec::len).sum::<usize>()
+        );
+        assert!(!gpx.contains("<desc>"));
+        assert!(line
+            .to_gpx(Crs::Gcj02)
+            .contains("<desc>Coordinates in GCJ-02</desc>"));
+    }
+
+    #[test]
+    fn test_from_payload() {
+        let payload = r#"{"routineLine": [{"longitude": 114.3, "latitude": 30.5}, {"longitude": 114.31, "latitude": 30.5}]}"#;
+        let line = RoutineLine::from_payload(payload).unwrap();
+        assert_eq!(line.segment_count(), 1);
+        assert!((line.length() - 0.96).abs() < 0.01);
+
+        assert!(RoutineLine::from_payload(r#"{"routineLine": []}"#).is_err());
+    }
+}
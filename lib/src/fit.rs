@@ -0,0 +1,414 @@
+/*
+    Pretty Der6y - A third-party running data upload client.
+    Copyright (C) 2024  Fay Ash
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published
+    by the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+
+use crate::{Error, Result, TrackPoint};
+
+/// Seconds between the Unix epoch and the FIT epoch, 1989-12-31T00:00:00Z.
+const FIT_EPOCH: i64 = 631_065_600;
+
+const SEMICIRCLES: f64 = 180. / 2_147_483_648.;
+
+const MESG_RECORD: u16 = 20;
+const MESG_EVENT: u16 = 21;
+
+const FIELD_TIMESTAMP: u8 = 253;
+const RECORD_POSITION_LAT: u8 = 0;
+const RECORD_POSITION_LONG: u8 = 1;
+const RECORD_DISTANCE: u8 = 5;
+const EVENT_EVENT: u8 = 0;
+const EVENT_EVENT_TYPE: u8 = 1;
+
+const EVENT_TIMER: u64 = 0;
+const EVENT_TYPE_STOP: u64 = 1;
+const EVENT_TYPE_STOP_ALL: u64 = 4;
+
+/// The record messages of a FIT activity, split wherever the timer stopped.
+pub(crate) struct Activity {
+    pub(crate) segments: Vec<Vec<TrackPoint>>,
+    /// Distance covered between the first and last record, in kilometers.
+    pub(crate) distance: Option<f64>,
+}
+
+struct Field {
+    number: u8,
+    size: usize,
+}
+
+struct Definition {
+    big_endian: bool,
+    global: u16,
+    fields: Vec<Field>,
+    developer_size: usize,
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, size: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.position..self.position + size)
+            .ok_or_else(|| Error::Route("Truncated FIT file".to_string()))?;
+        self.position += size;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+}
+
+fn crc(data: &[u8]) -> u16 {
+    const TABLE: [u16; 16] = [
+        0x0000, 0xCC01, 0xD801, 0x1400, 0xF001, 0x3C00, 0x2800, 0xE401, 0xA001, 0x6C00, 0x7800,
+        0xB401, 0x5000, 0x9C01, 0x8801, 0x4400,
+    ];
+
+    data.iter().fold(0, |crc, &byte| {
+        let crc = (crc >> 4) ^ TABLE[(crc & 0xF) as usize] ^ TABLE[(byte & 0xF) as usize];
+        (crc >> 4) ^ TABLE[(crc & 0xF) as usize] ^ TABLE[(byte >> 4) as usize]
+    })
+}
+
+/// Reads an unsigned integer field, or `None` if it holds the invalid value.
+fn unsigned(bytes: &[u8], big_endian: bool) -> Option<u64> {
+    if bytes.is_empty() || bytes.len() > 8 {
+        return None;
+    }
+
+    let mut value = 0u64;
+    for i in 0..bytes.len() {
+        let byte = if big_endian {
+            bytes[i]
+        } else {
+            bytes[bytes.len() - 1 - i]
+        };
+        value = value << 8 | byte as u64;
+    }
+
+    let invalid = u64::MAX >> (64 - 8 * bytes.len());
+    (value != invalid).then_some(value)
+}
+
+/// Reads a signed 32-bit field, or `None` if it holds the invalid value.
+fn signed(bytes: &[u8], big_endian: bool) -> Option<i32> {
+    let bytes: [u8; 4] = bytes.try_into().ok()?;
+    let value = if big_endian {
+        i32::from_be_bytes(bytes)
+    } else {
+        i32::from_le_bytes(bytes)
+    };
+    (value != i32::MAX).then_some(value)
+}
+
+fn timestamp(seconds: u64) -> Result<DateTime<Utc>> {
+    DateTime::from_timestamp(FIT_EPOCH + seconds as i64, 0)
+        .ok_or_else(|| Error::Route(format!("Invalid FIT timestamp: {}", seconds)))
+}
+
+/// Decodes the record and timer events of a FIT activity file.
+///
+/// Records without a position, such as those logged before a GPS fix, are
+/// skipped.
+pub(crate) fn parse(data: &[u8]) -> Result<Activity> {
+    let header_size = *data
+        .first()
+        .ok_or_else(|| Error::Route("Empty FIT file".to_string()))? as usize;
+    if header_size < 12 || data.len() < header_size || &data[8..12] != b".FIT" {
+        return Err(Error::Route("Not a FIT file".to_string()));
+    }
+
+    let data_size = u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize;
+    let end = header_size + data_size;
+    let file = data
+        .get(..end + 2)
+        .ok_or_else(|| Error::Route("Truncated FIT file".to_string()))?;
+    if crc(file) != 0 {
+        return Err(Error::Route("FIT file checksum mismatch".to_string()));
+    }
+
+    let mut cursor = Cursor {
+        data: &data[..end],
+        position: header_size,
+    };
+    let mut definitions: HashMap<u8, Definition> = HashMap::new();
+    let mut last_timestamp = None;
+
+    let mut segments = Vec::new();
+    let mut segment = Vec::new();
+    let mut first_distance = None;
+    let mut last_distance = None;
+
+    while cursor.position < end {
+        let header = cursor.byte()?;
+
+        if header & 0x40 != 0 && header & 0x80 == 0 {
+            let local = header & 0x0F;
+            cursor.take(1)?;
+            let big_endian = cursor.byte()? == 1;
+            let global = cursor.take(2)?;
+            let global = if big_endian {
+                u16::from_be_bytes([global[0], global[1]])
+            } else {
+                u16::from_le_bytes([global[0], global[1]])
+            };
+
+            let count = cursor.byte()?;
+            let fields = (0..count)
+                .map(|_| {
+                    let field = cursor.take(3)?;
+                    Ok(Field {
+                        number: field[0],
+                        size: field[1] as usize,
+                    })
+                })
+                .collect::<Result<_>>()?;
+
+            let mut developer_size = 0;
+            if header & 0x20 != 0 {
+                for _ in 0..cursor.byte()? {
+                    developer_size += cursor.take(3)?[1] as usize;
+                }
+            }
+
+            definitions.insert(
+                local,
+                Definition {
+                    big_endian,
+                    global,
+                    fields,
+                    developer_size,
+                },
+            );
+            continue;
+        }
+
+        // Compressed timestamp headers carry the low five bits of the time
+        // offset from the last full timestamp.
+        let (local, offset) = if header & 0x80 != 0 {
+            ((header >> 5) & 0x03, Some((header & 0x1F) as u64))
+        } else {
+            (header & 0x0F, None)
+        };
+        let definition = definitions
+            .get(&local)
+            .ok_or_else(|| Error::Route(format!("Undefined FIT message type {}", local)))?;
+
+        let mut values = HashMap::new();
+        for field in &definition.fields {
+            let bytes = cursor.take(field.size)?;
+            values.insert(field.number, bytes);
+        }
+        cursor.take(definition.developer_size)?;
+
+        let time = match (values.get(&FIELD_TIMESTAMP), offset) {
+            // Timestamps are 32-bit, wider ones would overflow the date.
+            (Some(bytes), _) if bytes.len() != 4 => {
+                return Err(Error::Route(format!(
+                    "Invalid FIT timestamp size: {} bytes",
+                    bytes.len()
+                )))
+            }
+            (Some(bytes), _) => unsigned(bytes, definition.big_endian),
+            (None, Some(offset)) => last_timestamp.map(|last: u64| {
+                let time = (last & !0x1F) | offset;
+                if time < last {
+                    time + 0x20
+                } else {
+                    time
+                }
+            }),
+            (None, None) => None,
+        };
+        if time.is_some() {
+            last_timestamp = time;
+        }
+
+        match definition.global {
+            MESG_RECORD => {
+                let big_endian = definition.big_endian;
+                if let Some(distance) = values
+                    .get(&RECORD_DISTANCE)
+                    .and_then(|bytes| unsigned(bytes, big_endian))
+                {
+                    first_distance.get_or_insert(distance);
+                    last_distance = Some(distance);
+                }
+
+                let position = values
+                    .get(&RECORD_POSITION_LAT)
+                    .and_then(|bytes| signed(bytes, big_endian))
+                    .zip(
+                        values
+                            .get(&RECORD_POSITION_LONG)
+                            .and_then(|bytes| signed(bytes, big_endian)),
+                    );
+                if let (Some((latitude, longitude)), Some(time)) = (position, time) {
+                    segment.push(TrackPoint {
+                        latitude: latitude as f64 * SEMICIRCLES,
+                        longitude: longitude as f64 * SEMICIRCLES,
+                        time: timestamp(time)?,
+                    });
+                }
+            }
+            MESG_EVENT => {
+                let field = |number| {
+                    values
+                        .get(&number)
+                        .and_then(|bytes| unsigned(bytes, definition.big_endian))
+                };
+                if field(EVENT_EVENT) == Some(EVENT_TIMER)
+                    && matches!(
+                        field(EVENT_EVENT_TYPE),
+                        Some(EVENT_TYPE_STOP | EVENT_TYPE_STOP_ALL)
+                    )
+                {
+                    segments.push(std::mem::take(&mut segment));
+                }
+            }
+            _ => {}
+        }
+    }
+    segments.push(segment);
+
+    let distance = match first_distance.zip(last_distance) {
+        Some((first, last)) => Some(last.checked_sub(first).ok_or_else(|| {
+            Error::Route(format!(
+                "FIT distance decreases from {} cm to {} cm",
+                first, last
+            ))
+        })?),
+        None => None,
+    };
+
+    Ok(Activity {
+        segments,
+        // Distance is recorded in centimeters.
+        distance: distance.map(|distance| distance as f64 / 100_000.),
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Encodes a FIT activity with one record per `(lat, lon, seconds,
+    /// centimeters)` and a timer stop event after each listed record index.
+    pub(crate) fn encode(records: &[(f64, f64, u32, u32)], stops: &[usize]) -> Vec<u8> {
+        let mut body = vec![
+            // Record definition, local type 0, little endian
+            0x40, 0, 0, 20, 0, 4, 253, 4, 0x86, 0, 4, 0x85, 1, 4, 0x85, 5, 4, 0x86,
+            // Event definition, local type 1, big endian
+            0x41, 0, 1, 0, 21, 3, 253, 4, 0x86, 0, 1, 0, 1, 1, 0,
+        ];
+
+        for (i, &(lat, lon, time, distance)) in records.iter().enumerate() {
+            body.push(0);
+            body.extend(time.to_le_bytes());
+            body.extend(((lat / SEMICIRCLES) as i32).to_le_bytes());
+            body.extend(((lon / SEMICIRCLES) as i32).to_le_bytes());
+            body.extend(distance.to_le_bytes());
+
+            if stops.contains(&i) {
+                body.push(1);
+                body.extend(time.to_be_bytes());
+                body.extend([EVENT_TIMER as u8, EVENT_TYPE_STOP_ALL as u8]);
+            }
+        }
+
+        frame(&body)
+    }
+
+    /// Wraps the messages of `body` in a FIT header and checksum.
+    fn frame(body: &[u8]) -> Vec<u8> {
+        let mut data = vec![14, 0x20, 0x08, 0x08];
+        data.extend((body.len() as u32).to_le_bytes());
+        data.extend(b".FIT");
+        data.extend(crc(&data).to_le_bytes());
+        data.extend(body);
+        data.extend(crc(&data).to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test_parse_records() {
+        let data = encode(
+            &[
+                (30.5, 114.3, 1_000_000_000, 0),
+                (30.501, 114.3, 1_000_000_030, 11_000),
+                (30.502, 114.3, 1_000_000_300, 22_000),
+            ],
+            &[1],
+        );
+
+        let activity = parse(&data).unwrap();
+        assert_eq!(activity.segments.len(), 2);
+        assert_eq!(activity.segments[0].len(), 2);
+        assert_eq!(activity.segments[1].len(), 1);
+        assert_eq!(activity.distance, Some(0.22));
+
+        let point = &activity.segments[0][1];
+        assert!((point.latitude - 30.501).abs() < 1e-6);
+        assert!((point.longitude - 114.3).abs() < 1e-6);
+        assert_eq!(
+            point.time,
+            "2021-09-08T01:47:10Z".parse::<DateTime<Utc>>().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(matches!(parse(b""), Err(Error::Route(_))));
+        assert!(matches!(parse(b"not a fit file"), Err(Error::Route(_))));
+
+        let mut data = encode(&[(30.5, 114.3, 1_000_000_000, 0)], &[]);
+        let last = data.len() - 3;
+        data[last] ^= 0xFF;
+        assert!(matches!(parse(&data), Err(Error::Route(_))));
+
+        let data = encode(
+            &[
+                (30.5, 114.3, 1_000_000_000, 22_000),
+                (30.501, 114.3, 1_000_000_030, 11_000),
+            ],
+            &[],
+        );
+        assert!(matches!(parse(&data), Err(Error::Route(e)) if e.contains("decreases")));
+
+        let data = encode(&[(30.5, 114.3, 1_000_000_000, 0)], &[]);
+        assert!(matches!(
+            parse(&data[..data.len() - 4]),
+            Err(Error::Route(_))
+        ));
+
+        // A record with a 64-bit timestamp too late to add to the epoch.
+        let mut body = vec![
+            0x40, 0, 0, 20, 0, 3, 253, 8, 0x8F, 0, 4, 0x85, 1, 4, 0x85, 0,
+        ];
+        body.extend((i64::MAX as u64).to_le_bytes());
+        body.extend([0; 8]);
+        assert!(matches!(parse(&frame(&body)), Err(Error::Route(_))));
+    }
+}
@@ -0,0 +1,151 @@
+/*
+    Pretty Der6y - A third-party running data upload client.
+    Copyright (C) 2024  Fay Ash
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published
+    by the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use chrono::{DateTime, Utc};
+use quick_xml::{
+    events::{BytesStart, Event},
+    Reader,
+};
+
+use crate::{Error, Result, TrackPoint};
+
+fn attribute(element: &BytesStart, name: &str) -> Result<f64> {
+    let value = element
+        .try_get_attribute(name)?
+        .ok_or_else(|| Error::Route(format!("Track point without {}", name)))?
+        .unescape_value()?;
+
+    value
+        .trim()
+        .parse()
+        .map_err(|_| Error::Route(format!("Invalid {}: {}", name, value)))
+}
+
+/// Reads every `<trkseg>` of every `<trk>` in a GPX 1.1 document.
+///
+/// Each `<trkpt>` must carry a `<time>`, since the recorded timing is the
+/// point of uploading a GPX file.
+pub(crate) fn parse(xml: &str) -> Result<Vec<Vec<TrackPoint>>> {
+    let mut reader = Reader::from_str(xml);
+    reader.config_mut().trim_text(true);
+
+    let mut segments = Vec::new();
+    let mut segment: Option<Vec<TrackPoint>> = None;
+    let mut position: Option<(f64, f64)> = None;
+    let mut time: Option<DateTime<Utc>> = None;
+
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) => match e.local_name().as_ref() {
+                b"trkseg" => segment = Some(Vec::new()),
+                b"trkpt" if segment.is_some() => {
+                    position = Some((attribute(&e, "lat")?, attribute(&e, "lon")?));
+                    time = None;
+                }
+                b"time" if position.is_some() => {
+                    let text = reader.read_text(e.name())?;
+                    let parsed = DateTime::parse_from_rfc3339(text.trim())
+                        .map_err(|_| Error::Route(format!("Invalid time: {}", text)))?;
+                    time = Some(parsed.with_timezone(&Utc));
+                }
+                _ => {}
+            },
+            Event::Empty(e) if e.local_name().as_ref() == b"trkpt" && segment.is_some() => {
+                return Err(Error::Route(format!(
+                    "Track point at {}, {} has no time",
+                    attribute(&e, "lat")?,
+                    attribute(&e, "lon")?
+                )));
+            }
+            Event::End(e) => match e.local_name().as_ref() {
+                b"trkpt" => {
+                    if let (Some((latitude, longitude)), Some(segment)) =
+                        (position.take(), segment.as_mut())
+                    {
+                        let time = time.take().ok_or_else(|| {
+                            Error::Route(format!(
+                                "Track point at {}, {} has no time",
+                                latitude, longitude
+                            ))
+                        })?;
+                        segment.push(TrackPoint {
+                            latitude,
+                            longitude,
+                            time,
+                        });
+                    }
+                }
+                b"trkseg" => segments.extend(segment.take()),
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GPX: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx version="1.1" creator="test" xmlns="http://www.topografix.com/GPX/1/1">
+  <metadata><time>2024-09-20T00:00:00Z</time></metadata>
+  <trk>
+    <name>Morning Run</name>
+    <trkseg>
+      <trkpt lat="30.5000" lon="114.3000"><ele>20</ele><time>2024-09-20T12:00:00Z</time></trkpt>
+      <trkpt lat="30.5010" lon="114.3000"><ele>20</ele><time>2024-09-20T12:00:30Z</time></trkpt>
+    </trkseg>
+    <trkseg>
+      <trkpt lat="30.5020" lon="114.3000"><time>2024-09-20T12:02:00+00:00</time></trkpt>
+      <trkpt lat="30.5030" lon="114.3000"><time>2024-09-20T12:02:40.500Z</time></trkpt>
+    </trkseg>
+  </trk>
+</gpx>"#;
+
+    #[test]
+    fn test_parse_segments() {
+        let segments = parse(GPX).unwrap();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].len(), 2);
+        assert_eq!(segments[1][1].latitude, 30.503);
+        assert_eq!(segments[1][1].longitude, 114.3);
+        assert_eq!(
+            segments[0][1].time,
+            "2024-09-20T12:00:30Z".parse::<DateTime<Utc>>().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_missing_time() {
+        let gpx = r#"<gpx><trk><trkseg><trkpt lat="30.5" lon="114.3"><ele>1</ele></trkpt></trkseg></trk></gpx>"#;
+        assert!(matches!(parse(gpx), Err(Error::Route(_))));
+
+        let gpx = r#"<gpx><trk><trkseg><trkpt lat="30.5" lon="114.3"/></trkseg></trk></gpx>"#;
+        assert!(matches!(parse(gpx), Err(Error::Route(_))));
+    }
+
+    #[test]
+    fn test_parse_invalid_coordinate() {
+        let gpx = r#"<gpx><trk><trkseg><trkpt lat="north" lon="114.3"><time>2024-09-20T12:00:00Z</time></trkpt></trkseg></trk></gpx>"#;
+        assert!(matches!(parse(gpx), Err(Error::Route(_))));
+    }
+}
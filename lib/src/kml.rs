@@ -0,0 +1,250 @@
+/*
+    Pretty Der6y - A third-party running data upload client.
+    Copyright (C) 2024  Fay Ash
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published
+    by the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::io::{Cursor, Read};
+
+use quick_xml::{
+    events::{BytesStart, Event},
+    Reader,
+};
+
+use crate::{
+    routine::{Candidate, Features},
+    Checkpoint, Error, Result,
+};
+
+fn coordinate(text: &str, separator: char) -> Result<Vec<f64>> {
+    let coordinate = text
+        .split(separator)
+        .filter(|value| !value.is_empty())
+        .map(|value| value.trim().parse())
+        .collect::<Result<Vec<f64>, _>>()
+        .map_err(|_| Error::Route(format!("Invalid coordinate: {}", text)))?;
+
+    if coordinate.len() < 2 {
+        return Err(Error::Route(format!("Invalid coordinate: {}", text)));
+    }
+    Ok(coordinate[..2].to_vec())
+}
+
+fn is_checkpoint_field(element: &BytesStart) -> Result<bool> {
+    Ok(match element.try_get_attribute("name")? {
+        Some(name) => name.unescape_value()? == "checkpoint",
+        None => false,
+    })
+}
+
+/// Collects every `LineString` and `gx:Track` of a KML document as lines,
+/// and every `Point` of a placemark whose `checkpoint` data field is `true`
+/// as a checkpoint, named after their placemark.
+pub(crate) fn parse(kml: &str) -> Result<Features> {
+    let mut reader = Reader::from_str(kml);
+    reader.config_mut().trim_text(true);
+
+    let mut features = Features::default();
+    let mut name: Option<String> = None;
+    let mut candidate: Option<Candidate> = None;
+    let mut in_point = false;
+    let mut point: Option<Vec<f64>> = None;
+    let mut checkpoint = false;
+    let mut in_checkpoint_field = false;
+
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) => match e.local_name().as_ref() {
+                b"Placemark" => {
+                    name = None;
+                    point = None;
+                    checkpoint = false;
+                }
+                b"Data" => in_checkpoint_field = is_checkpoint_field(&e)?,
+                b"value" if in_checkpoint_field => {
+                    checkpoint = reader.read_text(e.name())?.trim() == "true";
+                }
+                b"SimpleData" if is_checkpoint_field(&e)? => {
+                    checkpoint = reader.read_text(e.name())?.trim() == "true";
+                }
+                b"name" if candidate.is_none() => {
+                    name = Some(reader.read_text(e.name())?.trim().to_string())
+                }
+                b"LineString" | b"Track" => {
+                    candidate = Some(Candidate {
+                        name: name.clone(),
+                        coordinates: Vec::new(),
+                    })
+                }
+                b"Point" => in_point = true,
+                b"coordinates" => {
+                    let text = reader.read_text(e.name())?;
+                    if in_point {
+                        point = Some(coordinate(text.trim(), ',')?);
+                    } else if let Some(candidate) = candidate.as_mut() {
+                        for tuple in text.split_whitespace() {
+                            candidate.coordinates.push(coordinate(tuple, ',')?);
+                        }
+                    }
+                }
+                b"coord" => {
+                    let text = reader.read_text(e.name())?;
+                    if let Some(candidate) = candidate.as_mut() {
+                        candidate.coordinates.push(coordinate(&text, ' ')?);
+                    }
+                }
+                _ => {}
+            },
+            Event::End(e) => match e.local_name().as_ref() {
+                b"LineString" | b"Track" => features.candidates.extend(candidate.take()),
+                b"Point" => in_point = false,
+                b"Data" => in_checkpoint_field = false,
+                b"Placemark" => {
+                    if let Some(position) = point.take().filter(|_| checkpoint) {
+                        features.checkpoints.push(Checkpoint {
+                            latitude: position[1],
+                            longitude: position[0],
+                            name: name.clone(),
+                        });
+                    }
+                    name = None;
+                }
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(features)
+}
+
+/// Reads the main document of a KMZ archive, which is `doc.kml` or else the
+/// first `.kml` file in it.
+pub(crate) fn unzip(kmz: &[u8]) -> Result<String> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(kmz))?;
+
+    let name = archive
+        .file_names()
+        .filter(|name| name.to_lowercase().ends_with(".kml"))
+        .min_by_key(|name| *name != "doc.kml")
+        .ok_or_else(|| Error::Route("No KML document in KMZ archive".to_string()))?
+        .to_string();
+
+    let mut kml = String::new();
+    archive
+        .by_name(&name)?
+        .read_to_string(&mut kml)
+        .map_err(|e| Error::Route(e.to_string()))?;
+    Ok(kml)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    pub(crate) const KML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<kml xmlns="http://www.opengis.net/kml/2.2" xmlns:gx="http://www.google.com/kml/ext/2.2">
+  <Document>
+    <name>Campus</name>
+    <Placemark>
+      <name>Playground</name>
+      <LineString>
+        <coordinates>
+          114.3000,30.5000,0 114.3010,30.5000,0
+          114.3010,30.5010,0
+        </coordinates>
+      </LineString>
+    </Placemark>
+    <Placemark>
+      <name>Lake</name>
+      <gx:Track>
+        <when>2024-09-20T12:00:00Z</when>
+        <when>2024-09-20T12:00:30Z</when>
+        <gx:coord>114.3100 30.5100 20</gx:coord>
+        <gx:coord>114.3110 30.5100 20</gx:coord>
+      </gx:Track>
+    </Placemark>
+    <Placemark>
+      <name>Gate</name>
+      <ExtendedData><Data name="checkpoint"><value>true</value></Data></ExtendedData>
+      <Point><coordinates>114.3,30.5,0</coordinates></Point>
+    </Placemark>
+    <Placemark>
+      <name>Library</name>
+      <Point><coordinates>114.35,30.55,0</coordinates></Point>
+    </Placemark>
+  </Document>
+</kml>"#;
+
+    pub(crate) fn zip(name: &str, kml: &str) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        writer
+            .start_file(name, zip::write::SimpleFileOptions::default())
+            .unwrap();
+        writer.write_all(kml.as_bytes()).unwrap();
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_parse_candidates() {
+        let Features {
+            candidates,
+            checkpoints,
+            ..
+        } = parse(KML).unwrap();
+        assert_eq!(candidates.len(), 2);
+        assert_eq!(
+            checkpoints,
+            vec![Checkpoint {
+                latitude: 30.5,
+                longitude: 114.3,
+                name: Some("Gate".to_string()),
+            }]
+        );
+
+        assert_eq!(candidates[0].name.as_deref(), Some("Playground"));
+        assert_eq!(
+            candidates[0].coordinates,
+            vec![
+                vec![114.3, 30.5],
+                vec![114.301, 30.5],
+                vec![114.301, 30.501]
+            ]
+        );
+
+        assert_eq!(candidates[1].name.as_deref(), Some("Lake"));
+        assert_eq!(candidates[1].coordinates[1], vec![114.311, 30.51]);
+    }
+
+    #[test]
+    fn test_parse_invalid_coordinate() {
+        let kml = "<kml><Placemark><LineString><coordinates>114.3 30.5</coordinates></LineString></Placemark></kml>";
+        assert!(matches!(parse(kml), Err(Error::Route(_))));
+    }
+
+    #[test]
+    fn test_unzip() {
+        assert_eq!(unzip(&zip("doc.kml", KML)).unwrap(), KML);
+        assert_eq!(unzip(&zip("files/route.KML", KML)).unwrap(), KML);
+        assert!(matches!(
+            unzip(&zip("route.txt", KML)),
+            Err(Error::Route(_))
+        ));
+        assert!(matches!(unzip(b"not a zip"), Err(Error::Route(_))));
+    }
+}
@@ -16,47 +16,71 @@
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
+mod endpoint;
+mod error;
+mod export;
+mod fit;
+mod gpx;
+mod kml;
+mod limits;
+#[cfg(test)]
+mod mock;
+mod receipt;
+mod request;
+mod retry;
 mod routine;
 mod security;
+mod session;
+mod track;
 use const_format::formatcp;
 use log::{debug, info};
 use regex::Regex;
 use routine::*;
+pub use routine::{
+    BoundingBox, Checkpoint, Crs, Route, RouteInfo, RouteSelector, CHECKPOINT_RADIUS,
+    MAX_ROUTINE_POINTS, MIN_LAP_LENGTH, ROUTINE_TOLERANCE,
+};
 
 pub use chrono;
-use chrono::{DateTime, Duration, Local, Utc};
-use rand::{thread_rng, Rng};
-use reqwest::{header::*, Client, StatusCode};
-use security::{decode_ns, sign_run_data, UploadRunningInfoBuilder};
+use chrono::{DateTime, Duration, Utc};
+pub use chrono_tz;
+use chrono_tz::Tz;
+pub use endpoint::Endpoint;
+use endpoint::Target;
+pub use error::{Error, Result};
+pub use export::RoutineLine;
+pub use limits::{MileageCheck, MileageMode, RunningLimits, Semester};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+pub use receipt::UploadReceipt;
+use request::Source;
+pub use request::{RunType, UploadRequest, UploadRequestBuilder};
+use reqwest::{header::*, Client, Method, RequestBuilder, Response, StatusCode};
+pub use retry::RetryPolicy;
+use security::{decode_ns, sign_run_data, UploadRunningInfo, UploadRunningInfoBuilder};
+pub use security::{verify_payload, Mismatch};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::{collections::HashMap, error::Error};
-
-const URL_BASE: &str = uncaesar!("fshv.ohjbp.fq");
+pub use session::Session;
+use std::collections::HashMap;
+pub use track::{Track, TrackPoint};
 
-const URL_CURRENT: &str = formatcp!("https://{}/education/semester/getCurrent", URL_BASE);
+/// Time zone the official server reads run times in.
+pub const DEFAULT_TIMEZONE: Tz = chrono_tz::Asia::Shanghai;
 
-const URL_GET_RUNNING_LIMIT: &str = formatcp!("https://{}/running/app/getRunningLimit", URL_BASE);
-
-const URL_GET_VERSION: &str = formatcp!(
-    "https://{}/authorization/mobileApp/getLastVersion?platform=2",
-    URL_BASE
-);
-
-const URL_LOGIN: &str = formatcp!("https://{}/authorization/user/v2/manage/login", URL_BASE);
+const URL_BASE: &str = uncaesar!("fshv.ohjbp.fq");
 
-const URL_UPLOAD_RUNNING: &str = formatcp!("https://{}/running//app/v3/upload", URL_BASE);
+const URL_ORIGIN: &str = formatcp!("https://{}", URL_BASE);
 
 const ORGANIZATION: HeaderName = HeaderName::from_static("organization");
 
-const HEADERS: [(HeaderName, &str); 9] = [
+const HEADERS: [(HeaderName, &str); 8] = [
     (ACCEPT, "*/*"),
     (ACCEPT_ENCODING, "gzip, deflate, br"),
     (ACCEPT_LANGUAGE, "zh-CN, zh-Hans;q=0.9"),
     (AUTHORIZATION, ""),
     (CONNECTION, "keep-alive"),
     (CONTENT_TYPE, "application/json"),
-    (HOST, URL_BASE),
     (ORGANIZATION, ""),
     (USER_AGENT, "Mozilla/5.0 (iPhone; CPU iPhone OS 15_4_1 like Mac OSX) AppleWebKit/605.1.15 (KHTML, like Gecko) Mobile/15E148 Html15Plus/1.0 (Immersed/47) uni-app"),
 ];
@@ -64,56 +88,290 @@ const HEADERS: [(HeaderName, &str); 9] = [
 const CALORIE_PER_MILEAGE: f64 = 58.3;
 const PACE: f64 = 360.;
 const PACE_RANGE: f64 = 0.6;
+/// Seconds a generated run pauses between two segments.
+const PAUSE: std::ops::Range<i64> = 30..120;
+
+/// The distance and timing of a run, before it is signed.
+struct Run {
+    effective_mileage: f64,
+    total_mileage: f64,
+    /// Seconds spent running, pauses excluded.
+    keep_time: i64,
+    start_time: DateTime<Tz>,
+    end_time: DateTime<Tz>,
+    /// The points between each pause.
+    segments: Vec<Vec<LGPoint>>,
+}
+
+impl Run {
+    fn total_part(&self) -> Result<u8> {
+        u8::try_from(self.segments.len())
+            .map_err(|_| Error::Request(format!("Too many segments: {}", self.segments.len())))
+    }
+
+    /// Segments that cover some ground, as opposed to a lone point recorded
+    /// while standing still.
+    fn effective_part(&self) -> Result<u8> {
+        let parts = self
+            .segments
+            .iter()
+            .filter(|segment| segment.len() > 1)
+            .count();
+        u8::try_from(parts).map_err(|_| Error::Request(format!("Too many segments: {}", parts)))
+    }
+}
 
-fn format_json<T: Serialize>(json: T) -> Result<String, Box<dyn Error>> {
+fn format_json<T: Serialize>(json: T) -> Result<String> {
     let re = Regex::new(": ")?;
     let json = serde_json::to_string_pretty(&json)?;
 
     Ok(re.replace_all(&json, " : ").to_string())
 }
 
-#[derive(Clone, Default)]
+fn check_status(res: Response) -> Result<Response> {
+    let status = res.status();
+    if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+        return Err(Error::Unauthorized);
+    }
+
+    if status.is_client_error() || status.is_server_error() {
+        return Err(Error::Status(status));
+    }
+
+    Ok(res)
+}
+
+/// Reads the body of a response, treating auth failures reported in the
+/// body's `code` the same as a 401 status.
+async fn read_body(res: Response) -> Result<String> {
+    let res = check_status(res)?.text().await?;
+
+    #[derive(Deserialize)]
+    struct StatusResult {
+        code: Option<i64>,
+    }
+
+    if let Ok(StatusResult {
+        code: Some(401 | 403),
+    }) = serde_json::from_str(&res)
+    {
+        return Err(Error::Unauthorized);
+    }
+
+    Ok(res)
+}
+
+#[derive(Clone)]
+struct Credentials {
+    username: String,
+    password: String,
+}
+
+#[derive(Clone)]
 pub struct Account {
     client: Client,
-    daily: f64,
-    day: f64,
-    end: f64,
+    credentials: Option<Credentials>,
     headers: HeaderMap,
     id: String,
     school_id: String,
-    limitation: String,
-    scoring: u8,
-    semester: String,
-    start: f64,
+    endpoints: HashMap<Endpoint, Target>,
+    limits: RunningLimits,
+    mileage_mode: MileageMode,
+    organization: String,
+    retry: RetryPolicy,
+    semester: Semester,
+    timezone: Tz,
     token: String,
     version: String,
-    week: f64,
-    weekly: f64,
 }
 
-impl Account {
-    /// Creates a new [`Account`].
-    pub fn new() -> Self {
+/// Builds an [`Account`] that talks to a custom server.
+///
+/// Every endpoint is resolved against the base URL, unless it has its own
+/// override.
+#[derive(Clone, Debug, Default)]
+pub struct AccountBuilder {
+    base_url: Option<String>,
+    endpoints: HashMap<Endpoint, String>,
+    mileage_mode: MileageMode,
+    retry: RetryPolicy,
+    timezone: Option<Tz>,
+}
+
+impl AccountBuilder {
+    /// Sets the base URL of the API, e.g. `http://127.0.0.1:8080`.
+    pub fn base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = Some(base_url.into());
+        self
+    }
+
+    /// Sets the full URL of a single endpoint, ignoring the base URL.
+    pub fn endpoint(mut self, endpoint: Endpoint, url: impl Into<String>) -> Self {
+        self.endpoints.insert(endpoint, url.into());
+        self
+    }
+
+    /// Sets the timeouts and retries of all requests.
+    pub fn retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Sets what uploads do with a mileage beyond the running limits.
+    pub fn mileage_mode(mut self, mode: MileageMode) -> Self {
+        self.mileage_mode = mode;
+        self
+    }
+
+    /// Sets the time zone the server reads run times in, Asia/Shanghai by
+    /// default.
+    pub fn timezone(mut self, timezone: Tz) -> Self {
+        self.timezone = Some(timezone);
+        self
+    }
+
+    pub fn build(self) -> Result<Account> {
         let mut headers = HeaderMap::new();
         for (key, val) in HEADERS {
-            headers.insert(key, val.parse().unwrap());
+            headers.insert(key, val.parse()?);
         }
 
-        Self {
+        let base_url = self.base_url.as_deref().unwrap_or(URL_ORIGIN);
+        let endpoints = Endpoint::ALL
+            .into_iter()
+            .map(|endpoint| {
+                let target = match self.endpoints.get(&endpoint) {
+                    Some(url) => Target::parse(url)?,
+                    None => Target::join(base_url, endpoint)?,
+                };
+                Ok((endpoint, target))
+            })
+            .collect::<Result<_>>()?;
+
+        let client = Client::builder()
+            .connect_timeout(self.retry.connect_timeout)
+            .timeout(self.retry.timeout)
+            .build()?;
+
+        Ok(Account {
+            client,
             headers,
-            ..Default::default()
+            endpoints,
+            mileage_mode: self.mileage_mode,
+            retry: self.retry,
+            timezone: self.timezone.unwrap_or(DEFAULT_TIMEZONE),
+            credentials: None,
+            id: String::new(),
+            school_id: String::new(),
+            limits: RunningLimits::default(),
+            organization: String::new(),
+            semester: Semester::default(),
+            token: String::new(),
+            version: String::new(),
+        })
+    }
+
+    /// Builds the account and restores a saved [`Session`] into it, without
+    /// checking the token.
+    pub fn build_from_session(self, session: Session) -> Result<Account> {
+        let mut account = self.build()?;
+        account.apply_session(session)?;
+        Ok(account)
+    }
+}
+
+impl Default for Account {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Account {
+    /// Creates a new [`Account`] for the official server.
+    pub fn new() -> Self {
+        Self::builder()
+            .build()
+            .expect("Default endpoints should be valid")
+    }
+
+    pub fn builder() -> AccountBuilder {
+        AccountBuilder::default()
+    }
+
+    fn request(
+        &self,
+        method: Method,
+        endpoint: Endpoint,
+        mut headers: HeaderMap,
+    ) -> Result<RequestBuilder> {
+        let target = self
+            .endpoints
+            .get(&endpoint)
+            .ok_or_else(|| Error::Config(format!("No URL for endpoint {}", endpoint)))?;
+        headers.insert(HOST, target.host.clone());
+
+        Ok(self
+            .client
+            .request(method, target.url.clone())
+            .headers(headers))
+    }
+
+    /// Sends a request and reads its body, retrying transient failures as
+    /// allowed by the [`RetryPolicy`].
+    async fn send(&self, endpoint: Endpoint, request: RequestBuilder) -> Result<String> {
+        let attempts = self.retry.attempts(endpoint);
+        let mut attempt = 1;
+
+        loop {
+            let result = match request.try_clone() {
+                Some(request) => match request.send().await {
+                    Ok(res) => read_body(res).await,
+                    Err(e) => Err(e.into()),
+                },
+                None => return Err(Error::Config("Request cannot be retried".to_string())),
+            };
+
+            match result {
+                Err(e) if attempt < attempts && retry::is_transient(&e) => {
+                    let delay = self.retry.delay(attempt);
+                    debug!(
+                        "Request to {} failed ({}/{}): {}, retrying in {:?}",
+                        endpoint, attempt, attempts, e, delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
         }
     }
 
-    pub async fn login(&mut self, username: &str, password: &str) -> Result<(), Box<dyn Error>> {
+    /// Logs in and remembers the credentials, so that the account can log
+    /// in again by itself once the token expires.
+    pub async fn login(&mut self, username: &str, password: &str) -> Result<()> {
         self.set_token(username, password).await?;
         self.set_current().await?;
         self.set_version().await?;
         self.set_running_limit().await?;
+        self.credentials = Some(Credentials {
+            username: username.to_string(),
+            password: password.to_string(),
+        });
         Ok(())
     }
 
-    async fn set_token(&mut self, username: &str, password: &str) -> Result<(), Box<dyn Error>> {
+    /// Logs in again with the stored credentials after the token expired.
+    async fn relogin(&mut self) -> Result<()> {
+        let Some(credentials) = self.credentials.clone() else {
+            return Err(Error::ReloginRequired);
+        };
+
+        info!("Token expired, logging in again");
+        self.login(&credentials.username, &credentials.password)
+            .await
+    }
+
+    async fn set_token(&mut self, username: &str, password: &str) -> Result<()> {
         let sign_digital = security::hs(&format!("{}{}1", username, password));
 
         #[derive(Serialize)]
@@ -147,19 +405,13 @@ impl Account {
 
         let request = SecurityBody { t, pyd: encode_ns };
 
-        let res = self
-            .client
-            .post(URL_LOGIN)
-            .headers(self.headers.clone())
-            .json(&request)
-            .send()
-            .await?;
-
-        if res.status() == StatusCode::BAD_REQUEST {
-            return Err("Invalid account or password".into());
-        }
-
-        let res = res.error_for_status()?.text().await?;
+        let request = self
+            .request(Method::POST, Endpoint::Login, self.headers.clone())?
+            .json(&request);
+        let res = match self.send(Endpoint::Login, request).await {
+            Err(Error::Status(StatusCode::BAD_REQUEST)) => return Err(Error::Auth),
+            res => res?,
+        };
         debug!("Login response: {}", res);
 
         #[derive(Deserialize, Debug)]
@@ -186,58 +438,120 @@ impl Account {
         self.id = data.id;
         self.token = data.access_token;
         self.school_id = data.school_id;
+        self.organization = data.organization_id;
+        self.set_auth_headers()?;
+
+        info!("Get token successful!");
+        Ok(())
+    }
+
+    fn set_auth_headers(&mut self) -> Result<()> {
         self.headers
-            .insert(ORGANIZATION, data.organization_id.parse()?);
+            .insert(ORGANIZATION, self.organization.parse()?);
         self.headers
             .insert(AUTHORIZATION, format!("Bearer {}", self.token).parse()?);
-
-        info!("Get token successful!");
         Ok(())
     }
 
-    async fn set_current(&mut self) -> Result<(), Box<dyn Error>> {
-        let res = self
-            .client
-            .get(URL_CURRENT)
-            .headers(self.headers.clone())
-            .send()
-            .await?
-            .error_for_status()?
-            .text()
-            .await?;
+    /// Takes a snapshot of the login state, or `None` if not logged in yet.
+    pub fn export_session(&self) -> Option<Session> {
+        if self.token.is_empty() {
+            return None;
+        }
 
-        debug!("Current response: {}", res);
+        Some(Session {
+            token: self.token.clone(),
+            user_id: self.id.clone(),
+            school_id: self.school_id.clone(),
+            organization_id: self.organization.clone(),
+            semester: self.semester.clone(),
+            version: self.version.clone(),
+            limits: self.limits.clone(),
+        })
+    }
 
-        #[derive(Deserialize, Debug)]
-        struct CurrentData {
-            id: String,
+    /// Creates an [`Account`] for the official server from a saved [`Session`].
+    ///
+    /// The token is not checked, see [`Account::restore_session`]. Use
+    /// [`AccountBuilder::build_from_session`] for a custom server.
+    pub fn from_session(session: Session) -> Result<Self> {
+        Self::builder().build_from_session(session)
+    }
+
+    fn apply_session(&mut self, session: Session) -> Result<()> {
+        self.token = session.token;
+        self.id = session.user_id;
+        self.school_id = session.school_id;
+        self.organization = session.organization_id;
+        self.semester = session.semester;
+        self.version = session.version;
+        self.limits = session.limits;
+        self.set_auth_headers()
+    }
+
+    /// Restores a saved [`Session`] and checks its token by refreshing the
+    /// running limits.
+    ///
+    /// Returns `false`, and leaves the account logged out, if the server no
+    /// longer accepts the token.
+    pub async fn restore_session(&mut self, session: Session) -> Result<bool> {
+        self.apply_session(session)?;
+
+        match self.set_running_limit().await {
+            Ok(()) => {
+                info!("Restore session successful!");
+                Ok(true)
+            }
+            Err(Error::Unauthorized) => {
+                info!("Session expired");
+                self.token.clear();
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Restores a saved [`Session`], logging in again if its token is stale
+    /// or the session cannot be restored for any other reason.
+    pub async fn resume(&mut self, session: Session, username: &str, password: &str) -> Result<()> {
+        match self.restore_session(session).await {
+            Ok(true) => {}
+            Ok(false) => self.login(username, password).await?,
+            Err(e) => {
+                info!("Failed to restore session, logging in again: {}", e);
+                self.token.clear();
+                self.login(username, password).await?;
+            }
         }
+        self.credentials = Some(Credentials {
+            username: username.to_string(),
+            password: password.to_string(),
+        });
+        Ok(())
+    }
+
+    async fn set_current(&mut self) -> Result<()> {
+        let request = self.request(Method::GET, Endpoint::Current, self.headers.clone())?;
+        let res = self.send(Endpoint::Current, request).await?;
+
+        debug!("Current response: {}", res);
 
         #[derive(Deserialize)]
         struct CurrentResult {
-            data: Option<CurrentData>,
+            data: Option<Semester>,
         }
 
-        let data = serde_json::from_str::<CurrentResult>(&res)?
+        self.semester = serde_json::from_str::<CurrentResult>(&res)?
             .data
-            .ok_or("No current semester")?;
-
-        self.semester = data.id;
+            .ok_or_else(|| Error::Limit("No current semester".to_string()))?;
 
         info!("Get current successful!");
         Ok(())
     }
 
-    async fn set_version(&mut self) -> Result<(), Box<dyn Error>> {
-        let res = self
-            .client
-            .get(URL_GET_VERSION)
-            .headers(self.headers.clone())
-            .send()
-            .await?
-            .error_for_status()?
-            .text()
-            .await?;
+    async fn set_version(&mut self) -> Result<()> {
+        let request = self.request(Method::GET, Endpoint::Version, self.headers.clone())?;
+        let res = self.send(Endpoint::Version, request).await?;
 
         debug!("Version response: {}", res);
         #[derive(Deserialize, Debug)]
@@ -258,21 +572,15 @@ impl Account {
         Ok(())
     }
 
-    async fn set_running_limit(&mut self) -> Result<(), Box<dyn Error>> {
+    async fn set_running_limit(&mut self) -> Result<()> {
         let json = json!({
-            "semesterId": self.semester,
+            "semesterId": self.semester.id,
         });
 
-        let res = self
-            .client
-            .post(URL_GET_RUNNING_LIMIT)
-            .headers(self.headers.clone())
-            .json(&json)
-            .send()
-            .await?
-            .error_for_status()?
-            .text()
-            .await?;
+        let request = self
+            .request(Method::POST, Endpoint::RunningLimit, self.headers.clone())?
+            .json(&json);
+        let res = self.send(Endpoint::RunningLimit, request).await?;
 
         debug!("Running limits response: {}", res);
 
@@ -315,16 +623,16 @@ impl Account {
             data.total_week_mileage,
             data.weekly_mileage,
         ) {
-            self.daily = daily_mileage;
-            self.day = total_day_mileage.parse()?;
-            self.end = effective_mileage_end;
-            self.limitation = limitations_goals_sex_info_id;
-            self.scoring = scoring_type;
-            self.start = effective_mileage_start;
-            self.week = total_week_mileage.parse()?;
-            self.weekly = weekly_mileage;
+            self.limits.daily = daily_mileage;
+            self.limits.day = total_day_mileage.parse()?;
+            self.limits.end = effective_mileage_end;
+            self.limits.limitation = limitations_goals_sex_info_id;
+            self.limits.scoring = scoring_type;
+            self.limits.start = effective_mileage_start;
+            self.limits.week = total_week_mileage.parse()?;
+            self.limits.weekly = weekly_mileage;
         } else {
-            return Err("Semester not started yet.".into());
+            return Err(Error::Limit("Semester not started yet.".to_string()));
         }
 
         info!("Get running limitation successful!");
@@ -332,128 +640,275 @@ impl Account {
     }
 
     pub fn daily(&self) -> f64 {
-        self.daily
+        self.limits.daily
     }
 
+    /// The running limits fetched at login or by the last refresh.
+    pub fn running_limits(&self) -> &RunningLimits {
+        &self.limits
+    }
+
+    /// The semester fetched at login or by the last refresh.
+    pub fn semester(&self) -> &Semester {
+        &self.semester
+    }
+
+    /// Fetches the current semester and its running limits again, e.g. after
+    /// an upload, logging in again only if the token has expired.
+    pub async fn refresh_limits(&mut self) -> Result<()> {
+        let result = match self.set_current().await {
+            Ok(()) => self.set_running_limit().await,
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Err(Error::Unauthorized) => self.relogin().await,
+            result => result,
+        }
+    }
+
+    /// Uploads a run generated along the GeoJSON route, logging in again and
+    /// retrying once if the token has expired.
     pub async fn upload_running(
         &mut self,
         geojson_str: &str,
         mileage: f64,
-        end_time: &DateTime<Local>,
-    ) -> Result<(), Box<dyn Error>> {
-        let headers: HeaderMap<HeaderValue> = (&HashMap::<HeaderName, HeaderValue>::from([
-            (HOST, URL_BASE.parse()?),
-            (CONTENT_TYPE, "application/json".parse()?),
-            (ACCEPT, "*/*".parse()?),
-            (CONNECTION, "keep-alive".parse()?),
-            (
-                USER_AGENT,
-                format!(
-                    "QJGX/{} (com.ledreamer.legym; build:30000868; iOS 16.0.2) Alamofire/5.8.0",
-                    self.version
-                )
-                .parse()?,
-            ),
-            (
-                ACCEPT_ENCODING,
-                "br;q=1.0, gzip;q=0.9, deflate;q=0.8".parse()?,
-            ),
-            (
-                ACCEPT_LANGUAGE,
-                "zh-Hans-HK;q=1.0, zh-Hant-HK;q=0.9, yue-Hant-HK;q=0.8".parse()?,
-            ),
-            (AUTHORIZATION, format!("Bearer {}", &self.token).parse()?),
-        ]))
-            .try_into()?;
+        end_time: &DateTime<Tz>,
+    ) -> Result<UploadReceipt> {
+        let request = UploadRequest::builder()
+            .route(Route::from_geojson(geojson_str, None)?)
+            .distance(mileage)
+            .end_time(*end_time)
+            .build()?;
+        self.upload(&request).await
+    }
 
-        let mut mileage = mileage
-            .min(self.daily - self.day)
-            .min(self.weekly - self.week)
-            .min(self.end);
+    /// Uploads a run, logging in again and retrying once if the token has
+    /// expired.
+    ///
+    /// A route's distance is capped by the running limits, see
+    /// [`AccountBuilder::mileage_mode`]. A track only has its effective
+    /// mileage capped, while the total mileage is reported as recorded.
+    ///
+    /// Returns what the server recorded, along with how the mileage fit the
+    /// running limits.
+    pub async fn upload(&mut self, request: &UploadRequest) -> Result<UploadReceipt> {
+        let (json, check) = self.request_running_info(request)?;
+        self.upload_payload(&json, check).await
+    }
 
-        if mileage < self.start {
-            return Err(String::from("Effective mileage too low").into());
-        }
+    /// Time zone the server reads run times in, see
+    /// [`AccountBuilder::timezone`].
+    pub fn timezone(&self) -> Tz {
+        self.timezone
+    }
+
+    /// Checks a mileage against the running limits before uploading it.
+    pub fn check_mileage(&self, mileage: f64) -> MileageCheck {
+        self.limits.check(mileage)
+    }
+
+    /// Builds and signs the payload [`Account::upload`] would send, without
+    /// sending it.
+    pub fn preview(&self, request: &UploadRequest) -> Result<serde_json::Value> {
+        Ok(serde_json::to_value(self.request_running_info(request)?.0)?)
+    }
 
-        let keep_time = {
-            // WARN: Must make sure that the rng dies before the await call
-            let mut rng = thread_rng();
-            mileage += rng.gen_range(-0.02..-0.001);
-            (mileage * PACE) as i64 + rng.gen_range(-15..15)
+    fn request_running_info(
+        &self,
+        request: &UploadRequest,
+    ) -> Result<(UploadRunningInfo, MileageCheck)> {
+        let (run, check) = match &request.source {
+            Source::Route {
+                route,
+                distance,
+                end_time,
+            } => self.route_run(route, *distance, end_time, request.segments, request.seed)?,
+            Source::Track(track) => self.track_run(track)?,
         };
+        check_checkpoints(&run.segments, request.sign_points())?;
+        Ok((self.running_info(run, request)?, check))
+    }
 
-        let pace_range = PACE_RANGE;
+    fn route_run(
+        &self,
+        route: &Route,
+        mileage: f64,
+        end_time: &DateTime<Utc>,
+        segments: u8,
+        seed: Option<u64>,
+    ) -> Result<(Run, MileageCheck)> {
+        let mut rng = match seed {
+            Some(seed) => ChaCha8Rng::seed_from_u64(seed),
+            None => ChaCha8Rng::from_entropy(),
+        };
+        let check = self.check_mileage(mileage);
+        let mut mileage = check.resolve(self.mileage_mode)?;
+
+        mileage += rng.gen_range(-0.02..-0.001);
+        let keep_time = ((mileage * PACE) as i64).saturating_add(rng.gen_range(-15..15));
+        let pauses: i64 = (1..segments).map(|_| rng.gen_range(PAUSE)).sum();
+
+        let end_time = end_time.with_timezone(&self.timezone);
+        let start_time = keep_time
+            .checked_add(pauses + 8)
+            .and_then(Duration::try_seconds)
+            .and_then(|duration| end_time.checked_sub_signed(duration))
+            .ok_or_else(|| {
+                Error::Request(format!(
+                    "Invalid run duration of {} s and {} s of pauses for {} km",
+                    keep_time, pauses, mileage
+                ))
+            })?;
+
+        // Report the length of the line as generated, which is within
+        // ROUTINE_TOLERANCE of the mileage asked for, but never more than
+        // that, so the run stays within the running limits.
+        let line = match seed {
+            Some(seed) => route.routine_seeded(mileage, segments, seed)?,
+            None => route.routine_with(mileage, segments, &mut rng)?,
+        };
+        let length = line.length();
+        debug!("Generated {:.3} km for {:.3} km requested", length, mileage);
 
-        let start_time =
-            *end_time - Duration::try_seconds(keep_time + 8).ok_or("Invalid duration")?;
+        let length = length.min(mileage);
+        let run = Run {
+            effective_mileage: length,
+            total_mileage: length,
+            keep_time,
+            start_time,
+            end_time,
+            segments: line.into_segments(),
+        };
+        Ok((run, check))
+    }
 
-        let calorie = (CALORIE_PER_MILEAGE * mileage) as i64;
-        let ave_pace = (keep_time as f64 / mileage) as i64 * 1000;
-        let pace_number = (mileage * 1000. / pace_range / 2.) as i64;
+    fn track_run(&self, track: &Track) -> Result<(Run, MileageCheck)> {
+        let distance = track.distance();
+        let keep_time = track.keep_time();
+        if keep_time <= 0 {
+            return Err(Error::Route("Track has no duration".to_string()));
+        }
 
-        let sign_digital = security::hs(&format!(
-            "{}{}{}{}{}{}{}{}{}",
-            mileage,
-            1,
-            start_time.format("%Y-%m-%d %H:%M:%S"),
-            calorie,
-            ave_pace,
+        let check = self.check_mileage(distance);
+        let run = Run {
+            effective_mileage: check.resolve(self.mileage_mode)?,
+            total_mileage: distance,
             keep_time,
-            pace_number,
-            mileage,
-            1,
-        ));
+            start_time: track.start_time().with_timezone(&self.timezone),
+            end_time: track.end_time().with_timezone(&self.timezone),
+            segments: track
+                .segments()
+                .iter()
+                .map(|segment| {
+                    segment
+                        .iter()
+                        .map(|point| LGPoint::from_wgs84(point.latitude, point.longitude))
+                        .collect()
+                })
+                .collect(),
+        };
+        Ok((run, check))
+    }
+
+    fn running_info(&self, run: Run, request: &UploadRequest) -> Result<UploadRunningInfo> {
+        let pace_range = PACE_RANGE;
+
+        let calorie = (CALORIE_PER_MILEAGE * run.total_mileage) as i64;
+        let ave_pace = (run.keep_time as f64 / run.total_mileage) as i64 * 1000;
+        let pace_number = (run.total_mileage * 1000. / pace_range / 2.) as i64;
+        let start_time = run.start_time.format("%Y-%m-%d %H:%M:%S").to_string();
+        let total_part = run.total_part()?;
+        let effective_part = run.effective_part()?;
 
         let mut json = UploadRunningInfoBuilder::default()
             .app_version(self.version.clone())
             .ave_pace(ave_pace)
             .calorie(calorie)
             .device_type("iPhone 13 Pro".to_string())
-            .effective_mileage(mileage)
-            .effective_part(1)
-            .end_time(end_time.format("%Y-%m-%d %H:%M:%S").to_string())
-            .gps_mileage(mileage)
-            .keep_time(keep_time)
-            .limitations_goals_sex_info_id(self.limitation.clone())
+            .effective_mileage(run.effective_mileage)
+            .effective_part(effective_part)
+            .end_time(run.end_time.format("%Y-%m-%d %H:%M:%S").to_string())
+            .gps_mileage(run.total_mileage)
+            .keep_time(run.keep_time)
+            .limitations_goals_sex_info_id(self.limits.limitation.clone())
             .pace_number(pace_number)
             .pace_range(pace_range)
-            .routine_line(get_routine(mileage, geojson_str)?)
-            .scoring_type(self.scoring)
-            .semester_id(self.semester.clone())
-            .sign_digital(sign_digital)
-            .sign_point(vec![])
-            .start_time(start_time.format("%Y-%m-%d %H:%M:%S").to_string())
+            .routine_line(join_segments(&run.segments))
+            .scoring_type(self.limits.scoring)
+            .semester_id(self.semester.id.clone())
+            .sign_point(request.sign_points().iter().map(LGPoint::from).collect())
+            .start_time(start_time)
             .system_version("16.0.2".to_string())
-            .total_mileage(mileage)
-            .total_part(1)
-            .run_type("自由跑".to_string())
-            .build()?;
+            .total_mileage(run.total_mileage)
+            .total_part(total_part)
+            .run_type(request.run_type.as_str().to_string())
+            .build()
+            .map_err(|e| Error::Decode(e.to_string()))?;
 
-        sign_run_data(&mut json, &self.id, &self.school_id)?;
+        sign_run_data(&mut json, &self.id, &self.school_id, self.timezone)?;
 
         debug!("Upload running json: {}", format_json(&json)?);
 
-        let res = self
-            .client
-            .post(URL_UPLOAD_RUNNING)
-            .headers(headers)
-            .json(&json)
-            .send()
-            .await?
-            .error_for_status()?
-            .text()
-            .await?;
+        Ok(json)
+    }
+
+    fn upload_headers(&self) -> Result<HeaderMap> {
+        Ok(HeaderMap::from_iter([
+            (CONTENT_TYPE, "application/json".parse()?),
+            (ACCEPT, "*/*".parse()?),
+            (CONNECTION, "keep-alive".parse()?),
+            (
+                USER_AGENT,
+                format!(
+                    "QJGX/{} (com.ledreamer.legym; build:30000868; iOS 16.0.2) Alamofire/5.8.0",
+                    self.version
+                )
+                .parse()?,
+            ),
+            (
+                ACCEPT_ENCODING,
+                "br;q=1.0, gzip;q=0.9, deflate;q=0.8".parse()?,
+            ),
+            (
+                ACCEPT_LANGUAGE,
+                "zh-Hans-HK;q=1.0, zh-Hant-HK;q=0.9, yue-Hant-HK;q=0.8".parse()?,
+            ),
+            (AUTHORIZATION, format!("Bearer {}", &self.token).parse()?),
+        ]))
+    }
+
+    async fn upload_payload(
+        &mut self,
+        json: &UploadRunningInfo,
+        mileage: MileageCheck,
+    ) -> Result<UploadReceipt> {
+        let res = match self.try_upload_payload(json).await {
+            Err(Error::Unauthorized) => {
+                self.relogin().await?;
+                self.try_upload_payload(json).await
+            }
+            result => result,
+        }?;
 
         info!("Upload running successful!");
         debug!("Upload running response: {}", res);
-        Ok(())
+        Ok(UploadReceipt::parse(&res, mileage))
+    }
+
+    async fn try_upload_payload(&self, json: &UploadRunningInfo) -> Result<String> {
+        let request = self
+            .request(Method::POST, Endpoint::Upload, self.upload_headers()?)?
+            .json(json);
+        self.send(Endpoint::Upload, request).await
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
-    use std::env;
+    use chrono::NaiveDateTime;
+    use mock::MockServer;
 
     use log::{Level, Metadata, Record};
 
@@ -475,25 +930,719 @@ mod tests {
 
     static LOGGER: SimpleLogger = SimpleLogger;
 
+    fn init_logger() {
+        if log::set_logger(&LOGGER).is_ok() {
+            log::set_max_level(log::LevelFilter::Debug);
+        }
+    }
+
+    /// Compares `actual` with the file of that name in `lib/golden`, or
+    /// rewrites the file if `UPDATE_GOLDEN` is set.
+    pub(crate) fn assert_golden(name: &str, actual: &str) {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("golden")
+            .join(name);
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            std::fs::write(&path, actual).unwrap();
+        }
+        let expected = std::fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("{}: {}, run with UPDATE_GOLDEN=1", path.display(), e));
+        assert!(
+            expected == actual,
+            "{} is out of date, run with UPDATE_GOLDEN=1 to see the difference",
+            path.display()
+        );
+    }
+
+    fn mock_account(server: &MockServer) -> Account {
+        Account::builder()
+            .base_url(server.origin())
+            .build()
+            .unwrap()
+    }
+
+    fn fast_retry() -> RetryPolicy {
+        RetryPolicy {
+            base_delay: std::time::Duration::from_millis(1),
+            max_delay: std::time::Duration::from_millis(5),
+            ..Default::default()
+        }
+    }
+
     #[tokio::test]
-    async fn test_upload_running() {
-        log::set_logger(&LOGGER).unwrap();
+    async fn test_login() {
+        init_logger();
+
+        let server = MockServer::start().await;
+        let mut account = mock_account(&server);
+        account.login(mock::USERNAME, mock::PASSWORD).await.unwrap();
+
+        assert_eq!(account.id, mock::USER_ID);
+        assert_eq!(account.school_id, mock::SCHOOL_ID);
+        assert_eq!(account.semester().id(), mock::SEMESTER_ID);
+        assert_eq!(account.semester().name(), Some(mock::SEMESTER_NAME));
+        assert_eq!(account.version, mock::VERSION);
+        assert_eq!(account.daily(), 10.);
+    }
+
+    #[tokio::test]
+    async fn test_login_invalid_password() {
+        init_logger();
 
-        log::set_max_level(log::LevelFilter::Debug);
+        let server = MockServer::start().await;
+        let mut account = mock_account(&server);
+        let result = account.login(mock::USERNAME, "wrong").await;
 
-        let username = env::var("USERNAME").unwrap();
-        let password = env::var("PASSWORD").unwrap();
+        assert!(matches!(result, Err(Error::Auth)));
+    }
 
-        let mut account = Account::new();
-        account.login(&username, &password).await.unwrap();
+    #[test]
+    fn test_default_account() {
+        let account = Account::default();
+        for endpoint in Endpoint::ALL {
+            assert!(account.endpoints.contains_key(&endpoint), "{}", endpoint);
+        }
+        assert_eq!(account.timezone(), DEFAULT_TIMEZONE);
+    }
+
+    #[tokio::test]
+    async fn test_endpoint_override() {
+        init_logger();
+
+        let server = MockServer::start().await;
+        let mut account = Account::builder()
+            .base_url("http://127.0.0.1:1")
+            .retry_policy(RetryPolicy::none())
+            .endpoint(
+                Endpoint::Login,
+                format!("{}{}", server.origin(), Endpoint::Login.path()),
+            )
+            .build()
+            .unwrap();
+
+        account
+            .set_token(mock::USERNAME, mock::PASSWORD)
+            .await
+            .unwrap();
+        assert!(matches!(
+            account.set_current().await,
+            Err(Error::Network(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_session_roundtrip() {
+        init_logger();
+
+        let server = MockServer::start().await;
+        let mut account = mock_account(&server);
+        assert!(account.export_session().is_none());
+
+        account.login(mock::USERNAME, mock::PASSWORD).await.unwrap();
+        let path = std::env::temp_dir()
+            .join(format!("pretty-der6y-{}", std::process::id()))
+            .join("session.json");
+        account.export_session().unwrap().save(&path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let mut restored = mock_account(&server);
+        let session = Session::load(&path).unwrap();
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        assert!(restored.restore_session(session).await.unwrap());
+        assert_eq!(restored.id, mock::USER_ID);
+        assert_eq!(restored.semester(), account.semester());
+        assert_eq!(restored.daily(), 10.);
+        assert_eq!(server.logins(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_session_from_builder() {
+        init_logger();
+
+        let server = MockServer::start().await;
+        let mut account = mock_account(&server);
+        account.login(mock::USERNAME, mock::PASSWORD).await.unwrap();
+        let session = account.export_session().unwrap();
+
+        // The custom server and settings of the builder are kept.
+        let mut restored = Account::builder()
+            .base_url(server.origin())
+            .timezone(chrono_tz::UTC)
+            .build_from_session(session)
+            .unwrap();
+        assert_eq!(restored.timezone(), chrono_tz::UTC);
+        restored.refresh_limits().await.unwrap();
+        assert_eq!(restored.daily(), 10.);
+        assert_eq!(server.logins(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_session_stale() {
+        init_logger();
+
+        let server = MockServer::start().await;
+        let mut account = mock_account(&server);
+        account.login(mock::USERNAME, mock::PASSWORD).await.unwrap();
+        let session = account.export_session().unwrap();
+        server.expire_token();
+
+        let mut restored = mock_account(&server);
+        assert!(!restored.restore_session(session.clone()).await.unwrap());
+        assert!(restored.export_session().is_none());
+
+        restored
+            .resume(session, mock::USERNAME, mock::PASSWORD)
+            .await
+            .unwrap();
+        assert_eq!(server.logins(), 2);
+        assert!(restored.export_session().is_some());
+
+        // A session the server no longer makes sense of is replaced as well.
+        let mut session = restored.export_session().unwrap();
+        session.semester.id = "stale-semester".to_string();
+        let mut restored = mock_account(&server);
+        assert!(restored.restore_session(session.clone()).await.is_err());
+        restored
+            .resume(session, mock::USERNAME, mock::PASSWORD)
+            .await
+            .unwrap();
+        assert_eq!(server.logins(), 3);
+        assert_eq!(restored.semester().id(), mock::SEMESTER_ID);
+    }
+
+    #[tokio::test]
+    async fn test_upload_relogin() {
+        init_logger();
+
+        let server = MockServer::start().await;
+        let mut account = mock_account(&server);
+        account.login(mock::USERNAME, mock::PASSWORD).await.unwrap();
+        server.expire_token();
+
+        let geojson_str = include_str!("../../assets/map.geojson");
+        account
+            .upload_running(
+                geojson_str,
+                5.0,
+                &Utc::now().with_timezone(&DEFAULT_TIMEZONE),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(server.logins(), 2);
+        assert_eq!(server.uploads().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_upload_relogin_required() {
+        init_logger();
+
+        let server = MockServer::start().await;
+        let mut account = mock_account(&server);
+        account.login(mock::USERNAME, mock::PASSWORD).await.unwrap();
+
+        let mut restored = mock_account(&server);
+        assert!(restored
+            .restore_session(account.export_session().unwrap())
+            .await
+            .unwrap());
+        server.expire_token();
+
+        let geojson_str = include_str!("../../assets/map.geojson");
+        let result = restored
+            .upload_running(
+                geojson_str,
+                5.0,
+                &Utc::now().with_timezone(&DEFAULT_TIMEZONE),
+            )
+            .await;
+
+        assert!(matches!(result, Err(Error::ReloginRequired)));
+        assert!(server.uploads().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_retry_reads() {
+        init_logger();
+
+        let server = MockServer::start().await;
+        let mut account = Account::builder()
+            .base_url(server.origin())
+            .retry_policy(fast_retry())
+            .build()
+            .unwrap();
+        account.login(mock::USERNAME, mock::PASSWORD).await.unwrap();
+
+        let requests = server.requests();
+        server.fail_next(2);
+        account.set_current().await.unwrap();
+        assert_eq!(server.requests() - requests, 3);
+
+        let requests = server.requests();
+        server.fail_next(3);
+        assert!(matches!(
+            account.set_version().await,
+            Err(Error::Status(StatusCode::SERVICE_UNAVAILABLE))
+        ));
+        assert_eq!(server.requests() - requests, 3);
+    }
+
+    #[tokio::test]
+    async fn test_retry_upload_opt_in() {
+        init_logger();
+
+        let geojson_str = include_str!("../../assets/map.geojson");
+        let server = MockServer::start().await;
+
+        let mut account = Account::builder()
+            .base_url(server.origin())
+            .retry_policy(fast_retry())
+            .build()
+            .unwrap();
+        account.login(mock::USERNAME, mock::PASSWORD).await.unwrap();
+        server.fail_next(1);
+        assert!(matches!(
+            account
+                .upload_running(
+                    geojson_str,
+                    5.0,
+                    &Utc::now().with_timezone(&DEFAULT_TIMEZONE)
+                )
+                .await,
+            Err(Error::Status(StatusCode::SERVICE_UNAVAILABLE))
+        ));
+        assert!(server.uploads().is_empty());
+
+        let mut account = Account::builder()
+            .base_url(server.origin())
+            .retry_policy(RetryPolicy {
+                retry_upload: true,
+                ..fast_retry()
+            })
+            .build()
+            .unwrap();
+        account.login(mock::USERNAME, mock::PASSWORD).await.unwrap();
+        server.fail_next(1);
+        account
+            .upload_running(
+                geojson_str,
+                5.0,
+                &Utc::now().with_timezone(&DEFAULT_TIMEZONE),
+            )
+            .await
+            .unwrap();
+        assert_eq!(server.uploads().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_upload_running() {
+        init_logger();
+
+        let server = MockServer::start().await;
+        let mut account = mock_account(&server);
+        account.login(mock::USERNAME, mock::PASSWORD).await.unwrap();
 
         let geojson_str = include_str!("../../assets/map.geojson");
         let mileage = 5.0;
-        let end_time = Local::now();
+        let end_time = Utc::now().with_timezone(&DEFAULT_TIMEZONE);
 
         account
             .upload_running(geojson_str, mileage, &end_time)
             .await
             .unwrap();
+
+        let uploads = server.uploads();
+        assert_eq!(uploads.len(), 1);
+        assert_eq!(uploads[0]["semesterId"], mock::SEMESTER_ID);
+        assert!(uploads[0]["effectiveMileage"].as_f64().unwrap() < mileage);
+    }
+
+    #[tokio::test]
+    async fn test_upload_track() {
+        init_logger();
+
+        let server = MockServer::start().await;
+        let mut account = mock_account(&server);
+        account.login(mock::USERNAME, mock::PASSWORD).await.unwrap();
+
+        // Two segments of roughly 1.1 km each, run at 6 min/km.
+        let start = "2024-09-20T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let segment = |offset: i64| {
+            (0..=10)
+                .map(|i| TrackPoint {
+                    latitude: 30.5 + (offset * 10 + i) as f64 * 0.001,
+                    longitude: 114.3,
+                    time: start + Duration::seconds(offset * 600 + i * 40),
+                })
+                .collect::<Vec<_>>()
+        };
+        let track = Track::new(vec![segment(0), segment(1)]).unwrap();
+        let receipt = account
+            .upload(&UploadRequest::track(track.clone()))
+            .await
+            .unwrap();
+        assert_eq!(receipt.record_id.as_deref(), Some("record-1"));
+        assert!(receipt.is_effective());
+
+        let uploads = server.uploads();
+        assert_eq!(uploads.len(), 1);
+        let upload = &uploads[0];
+        assert_eq!(upload["keepTime"], 800);
+        assert_eq!(
+            upload["startTime"],
+            track
+                .start_time()
+                .with_timezone(&DEFAULT_TIMEZONE)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        );
+        assert_eq!(
+            upload["endTime"],
+            track
+                .end_time()
+                .with_timezone(&DEFAULT_TIMEZONE)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        );
+        assert_eq!(upload["totalMileage"].as_f64().unwrap(), track.distance());
+        // The second segment resumes where the first one paused, which is
+        // sent once.
+        assert_eq!(upload["routineLine"].as_array().unwrap().len(), 21);
+        assert_eq!(upload["totalPart"], 2);
+        assert_eq!(upload["effectivePart"], 2);
+    }
+
+    #[tokio::test]
+    async fn test_preview_segments() {
+        init_logger();
+
+        let server = MockServer::start().await;
+        let mut account = mock_account(&server);
+        account.login(mock::USERNAME, mock::PASSWORD).await.unwrap();
+
+        let end_time = Utc::now().with_timezone(&DEFAULT_TIMEZONE);
+        let request = UploadRequest::builder()
+            .route(Route::from_geojson(include_str!("../../assets/map.geojson"), None).unwrap())
+            .distance(3.0)
+            .end_time(end_time)
+            .segments(3)
+            .build()
+            .unwrap();
+        let payload = account.preview(&request).unwrap();
+
+        assert_eq!(payload["totalPart"], 3);
+        assert_eq!(payload["effectivePart"], 3);
+
+        // The line does not repeat the points where it paused.
+        let line = payload["routineLine"].as_array().unwrap();
+        assert!(line.windows(2).all(|pair| pair[0] != pair[1]));
+
+        // The reported mileage is the length of the line sent.
+        let line = RoutineLine::from_payload(&payload.to_string()).unwrap();
+        let mileage = payload["gpsMileage"].as_f64().unwrap();
+        assert!((line.length() - mileage).abs() < ROUTINE_TOLERANCE);
+        assert!((mileage - 3.0).abs() < 0.02 + ROUTINE_TOLERANCE);
+
+        // The pauses lie between the start and the end, but not in the keep
+        // time.
+        let start_time = NaiveDateTime::parse_from_str(
+            payload["startTime"].as_str().unwrap(),
+            "%Y-%m-%d %H:%M:%S",
+        )
+        .unwrap();
+        let elapsed = (end_time.naive_local() - start_time).num_seconds();
+        let keep_time = payload["keepTime"].as_i64().unwrap();
+        assert!(elapsed >= keep_time + 2 * PAUSE.start);
+        assert!(elapsed <= keep_time + 2 * PAUSE.end + 8);
+
+        // The encrypted summary agrees with the payload it is sent with.
+        let mismatches = verify_payload(
+            &payload.to_string(),
+            mock::USER_ID,
+            mock::SCHOOL_ID,
+            DEFAULT_TIMEZONE,
+        )
+        .unwrap();
+        assert!(mismatches.is_empty(), "{:?}", mismatches);
+
+        let point = |seconds| TrackPoint {
+            latitude: 30.5,
+            longitude: 114.3,
+            time: DateTime::UNIX_EPOCH + Duration::seconds(seconds),
+        };
+        let track = Track::new(vec![vec![point(0), point(60)]]).unwrap();
+        let track = UploadRequest::builder().track(track).segments(2).build();
+        assert!(matches!(track, Err(Error::Request(_))));
+    }
+
+    #[tokio::test]
+    async fn test_preview_invalid_duration() {
+        init_logger();
+
+        let server = MockServer::with_limits(mock::MockLimits {
+            daily: f64::MAX,
+            weekly: f64::MAX,
+            end: f64::MAX,
+            ..Default::default()
+        })
+        .await;
+        let mut account = mock_account(&server);
+        account.login(mock::USERNAME, mock::PASSWORD).await.unwrap();
+
+        let route = Route::from_geojson(include_str!("../../assets/map.geojson"), None).unwrap();
+        for distance in [1e16, 1e20] {
+            let request = UploadRequest::route(route.clone(), distance);
+            assert!(matches!(
+                account.preview(&request),
+                Err(Error::Request(message)) if message.contains("duration")
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_preview_route() {
+        init_logger();
+
+        let server = MockServer::start().await;
+        let mut account = mock_account(&server);
+        account.login(mock::USERNAME, mock::PASSWORD).await.unwrap();
+
+        let route = Route::from_geojson(include_str!("../../assets/map.geojson"), None).unwrap();
+        let end_time = Utc::now().with_timezone(&DEFAULT_TIMEZONE);
+        // The first point of the route.
+        let checkpoint = Checkpoint {
+            latitude: 30.82931170793215,
+            longitude: 104.18111684682418,
+            name: Some("Start".to_string()),
+        };
+        let request = UploadRequest::builder()
+            .route(route.clone())
+            .distance(5.0)
+            .end_time(end_time)
+            .segments(2)
+            .checkpoint(checkpoint)
+            .build()
+            .unwrap();
+        let payload = account.preview(&request).unwrap();
+
+        for field in ["routineLine", "signDigital", "oct", "signTime"] {
+            assert!(!payload[field].is_null(), "missing {}", field);
+        }
+        assert_eq!(
+            payload["endTime"],
+            end_time.format("%Y-%m-%d %H:%M:%S").to_string()
+        );
+        assert_eq!(payload["type"], "定点跑");
+        assert_eq!(payload["totalPart"], 2);
+        assert_eq!(payload["effectivePart"], 2);
+        assert_eq!(payload["signPoint"].as_array().unwrap().len(), 1);
+        assert!(server.uploads().is_empty());
+
+        let far_checkpoint = Checkpoint {
+            latitude: 30.5,
+            longitude: 114.3,
+            name: None,
+        };
+        let far_away = UploadRequest::builder()
+            .route(route.clone())
+            .distance(5.0)
+            .checkpoint(far_checkpoint.clone())
+            .build()
+            .unwrap();
+        assert!(matches!(account.preview(&far_away), Err(Error::Route(_))));
+
+        // A free run neither checks nor signs in at checkpoints.
+        let free = UploadRequest::builder()
+            .route(route)
+            .distance(5.0)
+            .run_type(RunType::Free)
+            .checkpoint(far_checkpoint)
+            .build()
+            .unwrap();
+        let payload = account.preview(&free).unwrap();
+        assert_eq!(payload["type"], "自由跑");
+        assert!(payload["signPoint"].as_array().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_preview_timezone() {
+        init_logger();
+
+        let server = MockServer::start().await;
+        let route = Route::from_geojson(include_str!("../../assets/map.geojson"), None).unwrap();
+        let end_time = "2024-09-20T12:15:08Z".parse::<DateTime<Utc>>().unwrap();
+        let request = UploadRequest::builder()
+            .route(route)
+            .distance(3.0)
+            .end_time(end_time)
+            .build()
+            .unwrap();
+
+        let mut account = mock_account(&server);
+        account.login(mock::USERNAME, mock::PASSWORD).await.unwrap();
+        assert_eq!(account.timezone(), DEFAULT_TIMEZONE);
+        let payload = account.preview(&request).unwrap();
+        assert_eq!(payload["endTime"], "2024-09-20 20:15:08");
+        assert!(payload["startTime"].as_str().unwrap() < "2024-09-20 20:15:08");
+
+        let mut account = Account::builder()
+            .base_url(server.origin())
+            .timezone(chrono_tz::Europe::Berlin)
+            .build()
+            .unwrap();
+        account.login(mock::USERNAME, mock::PASSWORD).await.unwrap();
+        let payload = account.preview(&request).unwrap();
+        assert_eq!(payload["endTime"], "2024-09-20 14:15:08");
+        assert!(verify_payload(
+            &payload.to_string(),
+            mock::USER_ID,
+            mock::SCHOOL_ID,
+            chrono_tz::Europe::Berlin
+        )
+        .unwrap()
+        .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_preview_seed() {
+        init_logger();
+
+        let server = MockServer::start().await;
+        let mut account = mock_account(&server);
+        account.login(mock::USERNAME, mock::PASSWORD).await.unwrap();
+
+        let route = Route::from_geojson(include_str!("../../assets/map.geojson"), None).unwrap();
+        let end_time = "2024-09-20T12:15:08Z".parse::<DateTime<Utc>>().unwrap();
+        let request = |seed| {
+            UploadRequest::builder()
+                .route(route.clone())
+                .distance(3.0)
+                .end_time(end_time)
+                .segments(2)
+                .seed(seed)
+                .build()
+                .unwrap()
+        };
+
+        let payload =
+            serde_json::to_string_pretty(&account.preview(&request(42)).unwrap()).unwrap();
+        assert_golden("payload.json", &payload);
+
+        let again = serde_json::to_string_pretty(&account.preview(&request(42)).unwrap()).unwrap();
+        assert_eq!(payload, again);
+        assert_ne!(
+            account.preview(&request(43)).unwrap()["routineLine"],
+            account.preview(&request(42)).unwrap()["routineLine"]
+        );
+
+        // An export with the same seed shows the same points, up to where
+        // the slightly shorter run ends.
+        let points = |line: RoutineLine| {
+            let mut points = line.into_segments().concat();
+            points.dedup();
+            points
+        };
+        let sent = points(RoutineLine::from_payload(&payload).unwrap());
+        let exported = points(route.routine_seeded(3.0, 2, 42).unwrap());
+        let shared = sent.len() - 1;
+        assert_eq!(sent[..shared], exported[..shared]);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_limits() {
+        init_logger();
+
+        let server = MockServer::start().await;
+        let mut account = mock_account(&server);
+        account.login(mock::USERNAME, mock::PASSWORD).await.unwrap();
+
+        let limits = account.running_limits();
+        assert_eq!(limits.weekly(), 30.);
+        assert_eq!(limits.effective_start(), 1.);
+        assert_eq!(limits.effective_end(), 8.);
+        assert_eq!(limits.remaining_daily(), 10.);
+
+        server.set_limits(mock::MockLimits {
+            day: 4.,
+            week: 28.,
+            ..Default::default()
+        });
+        account.refresh_limits().await.unwrap();
+
+        let limits = account.running_limits();
+        assert_eq!(limits.today(), 4.);
+        assert_eq!(limits.remaining_daily(), 6.);
+        assert_eq!(limits.remaining_weekly(), 2.);
+        assert_eq!(server.logins(), 1);
+
+        server.expire_token();
+        account.refresh_limits().await.unwrap();
+        assert_eq!(server.logins(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_upload_mileage_mode() {
+        init_logger();
+
+        let geojson_str = include_str!("../../assets/map.geojson");
+        let server = MockServer::with_limits(mock::MockLimits {
+            day: 7.,
+            ..Default::default()
+        })
+        .await;
+
+        let mut account = mock_account(&server);
+        account.login(mock::USERNAME, mock::PASSWORD).await.unwrap();
+        let check = account
+            .upload_running(
+                geojson_str,
+                5.0,
+                &Utc::now().with_timezone(&DEFAULT_TIMEZONE),
+            )
+            .await
+            .unwrap()
+            .mileage;
+        assert!(check.is_clamped());
+        assert!(!check.within_daily);
+        assert_eq!(check.adjusted, 3.);
+        // Neither mileage exceeds the limit, whatever the jitter of the line.
+        for field in ["effectiveMileage", "gpsMileage", "totalMileage"] {
+            assert!(server.uploads()[0][field].as_f64().unwrap() < 3.);
+        }
+        let route = Route::from_geojson(geojson_str, None).unwrap();
+        for seed in 0..20 {
+            let request = UploadRequest::builder()
+                .route(route.clone())
+                .distance(3.)
+                .seed(seed)
+                .build()
+                .unwrap();
+            let payload = account.preview(&request).unwrap();
+            assert!(payload["gpsMileage"].as_f64().unwrap() < 3.);
+            assert_eq!(payload["gpsMileage"], payload["effectiveMileage"]);
+        }
+
+        let mut account = Account::builder()
+            .base_url(server.origin())
+            .mileage_mode(MileageMode::Strict)
+            .build()
+            .unwrap();
+        account.login(mock::USERNAME, mock::PASSWORD).await.unwrap();
+        assert!(matches!(
+            account
+                .upload_running(
+                    geojson_str,
+                    5.0,
+                    &Utc::now().with_timezone(&DEFAULT_TIMEZONE)
+                )
+                .await,
+            Err(Error::Limit(_))
+        ));
+        assert_eq!(server.uploads().len(), 1);
     }
 }
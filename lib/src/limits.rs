@@ -0,0 +1,247 @@
+/*
+    Pretty Der6y - A third-party running data upload client.
+    Copyright (C) 2024  Fay Ash
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published
+    by the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Result};
+
+/// Running limits of the current semester, as reported by the server.
+///
+/// Mileages are in kilometers.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunningLimits {
+    pub(crate) daily: f64,
+    pub(crate) day: f64,
+    pub(crate) end: f64,
+    pub(crate) limitation: String,
+    pub(crate) scoring: u8,
+    pub(crate) start: f64,
+    pub(crate) week: f64,
+    pub(crate) weekly: f64,
+}
+
+impl RunningLimits {
+    /// Mileage that counts per day.
+    pub fn daily(&self) -> f64 {
+        self.daily
+    }
+
+    /// Mileage that counts per week.
+    pub fn weekly(&self) -> f64 {
+        self.weekly
+    }
+
+    /// Mileage already run today.
+    pub fn today(&self) -> f64 {
+        self.day
+    }
+
+    /// Mileage already run this week.
+    pub fn this_week(&self) -> f64 {
+        self.week
+    }
+
+    /// Shortest run that counts.
+    pub fn effective_start(&self) -> f64 {
+        self.start
+    }
+
+    /// Longest mileage a single run counts for.
+    pub fn effective_end(&self) -> f64 {
+        self.end
+    }
+
+    pub fn scoring_type(&self) -> u8 {
+        self.scoring
+    }
+
+    /// Mileage that still counts today.
+    pub fn remaining_daily(&self) -> f64 {
+        (self.daily - self.day).max(0.)
+    }
+
+    /// Mileage that still counts this week.
+    pub fn remaining_weekly(&self) -> f64 {
+        (self.weekly - self.week).max(0.)
+    }
+
+    /// Checks a requested mileage against the caps and the effective range.
+    pub fn check(&self, mileage: f64) -> MileageCheck {
+        MileageCheck {
+            requested: mileage,
+            adjusted: mileage
+                .min(self.remaining_daily())
+                .min(self.remaining_weekly())
+                .min(self.end),
+            within_daily: mileage <= self.remaining_daily(),
+            within_weekly: mileage <= self.remaining_weekly(),
+            within_range: (self.start..=self.end).contains(&mileage),
+            effective_start: self.start,
+        }
+    }
+}
+
+/// The current semester, as reported by the server.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Semester {
+    pub(crate) id: String,
+    #[serde(default)]
+    pub(crate) name: Option<String>,
+}
+
+impl Semester {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+}
+
+/// What to do with a mileage that exceeds the running limits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MileageMode {
+    /// Upload the largest mileage that still counts.
+    #[default]
+    Clamp,
+    /// Refuse to upload.
+    Strict,
+}
+
+/// How a requested mileage fits the running limits, see
+/// [`RunningLimits::check`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MileageCheck {
+    pub requested: f64,
+    /// The mileage that counts, capped by the limits.
+    pub adjusted: f64,
+    /// Whether the mileage fits what is left of the daily cap.
+    pub within_daily: bool,
+    /// Whether the mileage fits what is left of the weekly cap.
+    pub within_weekly: bool,
+    /// Whether the mileage lies between the shortest and longest run that
+    /// count.
+    pub within_range: bool,
+    effective_start: f64,
+}
+
+impl MileageCheck {
+    /// Whether the mileage would be recorded shorter than requested.
+    pub fn is_clamped(&self) -> bool {
+        self.adjusted < self.requested
+    }
+
+    /// Returns the mileage to upload in the given mode.
+    ///
+    /// Fails if even the adjusted mileage is too short to count, or in strict
+    /// mode if it had to be adjusted at all.
+    pub fn resolve(&self, mode: MileageMode) -> Result<f64> {
+        if self.adjusted < self.effective_start {
+            return Err(Error::Limit(format!(
+                "Effective mileage too low: {:.2} km counts, at least {:.2} km needed",
+                self.adjusted, self.effective_start
+            )));
+        }
+
+        if mode == MileageMode::Strict && self.is_clamped() {
+            let mut reasons = Vec::new();
+            if !self.within_daily {
+                reasons.push("the daily cap");
+            }
+            if !self.within_weekly {
+                reasons.push("the weekly cap");
+            }
+            if !self.within_range {
+                reasons.push("the longest run that counts");
+            }
+            return Err(Error::Limit(format!(
+                "{:.2} km exceeds {}, only {:.2} km would count",
+                self.requested,
+                reasons.join(" and "),
+                self.adjusted
+            )));
+        }
+
+        Ok(self.adjusted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> RunningLimits {
+        RunningLimits {
+            daily: 10.,
+            day: 4.,
+            end: 8.,
+            start: 1.,
+            week: 25.,
+            weekly: 30.,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_check_within() {
+        let check = limits().check(3.);
+        assert!(check.within_daily && check.within_weekly && check.within_range);
+        assert!(!check.is_clamped());
+        assert_eq!(check.resolve(MileageMode::Strict).unwrap(), 3.);
+    }
+
+    #[test]
+    fn test_check_clamped() {
+        let check = limits().check(7.);
+        assert!(!check.within_daily);
+        assert!(!check.within_weekly);
+        assert!(check.within_range);
+        assert_eq!(check.adjusted, 5.);
+        assert_eq!(check.resolve(MileageMode::Clamp).unwrap(), 5.);
+
+        let Err(Error::Limit(message)) = check.resolve(MileageMode::Strict) else {
+            panic!("strict mode should refuse a clamped mileage");
+        };
+        assert!(message.contains("the daily cap and the weekly cap"));
+    }
+
+    #[test]
+    fn test_check_too_short() {
+        let check = limits().check(0.5);
+        assert!(!check.within_range);
+        assert!(!check.is_clamped());
+        assert!(matches!(
+            check.resolve(MileageMode::Clamp),
+            Err(Error::Limit(_))
+        ));
+
+        let limits = RunningLimits {
+            day: 9.5,
+            ..limits()
+        };
+        assert!(matches!(
+            limits.check(3.).resolve(MileageMode::Clamp),
+            Err(Error::Limit(_))
+        ));
+    }
+}
@@ -0,0 +1,337 @@
+/*
+    Pretty Der6y - A third-party running data upload client.
+    Copyright (C) 2024  Fay Ash
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published
+    by the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! An offline stand-in for the running API, so the whole login and upload
+//! flow can be tested without network access or real credentials.
+
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::{net::TcpListener, task::JoinHandle};
+
+use crate::{
+    security::{self, decode_ns, encode_ns},
+    Endpoint, DEFAULT_TIMEZONE,
+};
+
+pub const USERNAME: &str = "13800000000";
+pub const PASSWORD: &str = "password";
+pub const USER_ID: &str = "4028818e8f0a1b2c3d4e5f60";
+pub const SCHOOL_ID: &str = "402881ea7c39c5d5017c39d143a30506";
+const ORGANIZATION_ID: &str = "402881ea7c39c5d5017c39d143a30507";
+pub const SEMESTER_ID: &str = "402881ea8f0a1b2c3d4e5f6000000001";
+pub const SEMESTER_NAME: &str = "2024-2025学年第一学期";
+pub const VERSION: &str = "3.10.0";
+
+/// Running limits reported by `getRunningLimit`.
+#[derive(Clone, Debug)]
+pub struct MockLimits {
+    pub daily: f64,
+    pub weekly: f64,
+    pub start: f64,
+    pub end: f64,
+    pub day: f64,
+    pub week: f64,
+}
+
+impl Default for MockLimits {
+    fn default() -> Self {
+        Self {
+            daily: 10.,
+            weekly: 30.,
+            start: 1.,
+            end: 8.,
+            day: 0.,
+            week: 0.,
+        }
+    }
+}
+
+#[derive(Default)]
+struct MockState {
+    failures: usize,
+    limits: MockLimits,
+    logins: usize,
+    requests: usize,
+    token: Option<String>,
+    uploads: Vec<Value>,
+}
+
+type Shared = Arc<Mutex<MockState>>;
+
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Shared,
+    handle: JoinHandle<()>,
+}
+
+impl MockServer {
+    /// Starts the server on a random local port.
+    pub async fn start() -> Self {
+        Self::with_limits(MockLimits::default()).await
+    }
+
+    pub async fn with_limits(limits: MockLimits) -> Self {
+        let state = Arc::new(Mutex::new(MockState {
+            limits,
+            ..Default::default()
+        }));
+
+        let app = Router::new()
+            .route(Endpoint::Login.path(), post(login))
+            .route(Endpoint::Current.path(), get(current))
+            .route(
+                Endpoint::Version.path().split('?').next().unwrap(),
+                get(version),
+            )
+            .route(Endpoint::RunningLimit.path(), post(running_limit))
+            .route(Endpoint::Upload.path(), post(upload))
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                inject_failures,
+            ))
+            .with_state(state.clone());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            axum::serve(listener, app).await.unwrap();
+        });
+
+        Self {
+            addr,
+            state,
+            handle,
+        }
+    }
+
+    /// The origin to send requests to, e.g. `http://127.0.0.1:4000`.
+    pub fn origin(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Answers the next `count` requests with `503 Service Unavailable`.
+    pub fn fail_next(&self, count: usize) {
+        self.state.lock().unwrap().failures = count;
+    }
+
+    /// How many requests were received, including failed ones.
+    pub fn requests(&self) -> usize {
+        self.state.lock().unwrap().requests
+    }
+
+    /// Changes the running limits reported from now on.
+    pub fn set_limits(&self, limits: MockLimits) {
+        self.state.lock().unwrap().limits = limits;
+    }
+
+    /// Invalidates the token handed out by the last login.
+    pub fn expire_token(&self) {
+        self.state.lock().unwrap().token = None;
+    }
+
+    /// How many times the login endpoint succeeded.
+    pub fn logins(&self) -> usize {
+        self.state.lock().unwrap().logins
+    }
+
+    /// The upload payloads accepted so far.
+    pub fn uploads(&self) -> Vec<Value> {
+        self.state.lock().unwrap().uploads.clone()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+async fn inject_failures(State(state): State<Shared>, request: Request, next: Next) -> Response {
+    {
+        let mut state = state.lock().unwrap();
+        state.requests += 1;
+        if state.failures > 0 {
+            state.failures -= 1;
+            return StatusCode::SERVICE_UNAVAILABLE.into_response();
+        }
+    }
+
+    next.run(request).await
+}
+
+fn authorized(state: &Shared, headers: &HeaderMap) -> Result<(), StatusCode> {
+    let state = state.lock().unwrap();
+    let expected = state
+        .token
+        .as_ref()
+        .map(|token| format!("Bearer {}", token));
+    match (headers.get("authorization"), expected) {
+        (Some(value), Some(expected)) if value == expected.as_str() => Ok(()),
+        _ => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+#[derive(Deserialize)]
+struct SecurityBody {
+    t: i64,
+    pyd: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LoginRequest {
+    entrance: String,
+    user_name: String,
+    password: String,
+    sign_digital: String,
+}
+
+async fn login(
+    State(state): State<Shared>,
+    Json(body): Json<SecurityBody>,
+) -> Result<Json<Value>, StatusCode> {
+    let request = decode_ns(&body.pyd, body.t).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let request: LoginRequest =
+        serde_json::from_str(&request).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let sign_digital = security::hs(&format!("{}{}1", request.user_name, request.password));
+    if request.entrance != "1"
+        || request.user_name != USERNAME
+        || request.password != PASSWORD
+        || request.sign_digital != sign_digital
+    {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let token = {
+        let mut state = state.lock().unwrap();
+        state.logins += 1;
+        let token = format!("mock-access-token-{}", state.logins);
+        state.token = Some(token.clone());
+        token
+    };
+
+    let data = json!({
+        "id": USER_ID,
+        "organizationId": ORGANIZATION_ID,
+        "accessToken": token,
+        "schoolId": SCHOOL_ID,
+    });
+
+    let t = Utc::now().timestamp_millis();
+    let pyd = encode_ns(&data.to_string(), t).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(json!({ "code": 0, "data": { "t": t, "pyd": pyd } })))
+}
+
+async fn current(
+    State(state): State<Shared>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, StatusCode> {
+    authorized(&state, &headers)?;
+    Ok(Json(
+        json!({ "code": 0, "data": { "id": SEMESTER_ID, "name": SEMESTER_NAME } }),
+    ))
+}
+
+async fn version(
+    State(state): State<Shared>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, StatusCode> {
+    authorized(&state, &headers)?;
+    Ok(Json(
+        json!({ "code": 0, "data": { "versionLabel": VERSION } }),
+    ))
+}
+
+async fn running_limit(
+    State(state): State<Shared>,
+    headers: HeaderMap,
+    Json(body): Json<Value>,
+) -> Result<Json<Value>, StatusCode> {
+    authorized(&state, &headers)?;
+    if body["semesterId"] != SEMESTER_ID {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let limits = state.lock().unwrap().limits.clone();
+    Ok(Json(json!({
+        "code": 0,
+        "data": {
+            "dailyMileage": limits.daily,
+            "effectiveMileageEnd": limits.end,
+            "effectiveMileageStart": limits.start,
+            "limitationsGoalsSexInfoId": "402881ea7c39c5d5017c39d143a30508",
+            "scoringType": 1,
+            "totalDayMileage": limits.day.to_string(),
+            "totalWeekMileage": limits.week.to_string(),
+            "weeklyMileage": limits.weekly,
+        }
+    })))
+}
+
+/// Checks that `oct` decrypts to a summary matching the payload and that
+/// `signDigital` matches the payload fields it is derived from.
+fn verify_upload(payload: &Value) -> Result<(), String> {
+    let mismatches =
+        security::verify_payload(&payload.to_string(), USER_ID, SCHOOL_ID, DEFAULT_TIMEZONE)
+            .map_err(|e| e.to_string())?;
+
+    match mismatches.first() {
+        Some(mismatch) => Err(format!(
+            "{} signed as {} but found {}",
+            mismatch.field, mismatch.signed, mismatch.found
+        )),
+        None => Ok(()),
+    }
+}
+
+async fn upload(
+    State(state): State<Shared>,
+    headers: HeaderMap,
+    Json(payload): Json<Value>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    authorized(&state, &headers).map_err(|status| (status, String::new()))?;
+    verify_upload(&payload).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+
+    let mut state = state.lock().unwrap();
+    state.uploads.push(payload.clone());
+    Ok(Json(json!({
+        "code": 0,
+        "data": {
+            "id": format!("record-{}", state.uploads.len()),
+            "effectiveMileage": payload["effectiveMileage"],
+            "isEffective": true,
+            "uneffectiveReason": "",
+        }
+    })))
+}
@@ -0,0 +1,147 @@
+/*
+    Pretty Der6y - A third-party running data upload client.
+    Copyright (C) 2024  Fay Ash
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published
+    by the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use log::warn;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::MileageCheck;
+
+/// What the server recorded for an upload.
+///
+/// Fields the server leaves out of its response are `None`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadReceipt {
+    /// Id of the running record.
+    pub record_id: Option<String>,
+    /// Mileage the record counts for, in kilometers.
+    pub effective_mileage: Option<f64>,
+    /// Whether the run counts at all.
+    pub effective: Option<bool>,
+    /// Why the run does not count, if it does not.
+    pub uneffective_reason: Option<String>,
+    /// How the requested mileage fit the running limits before uploading.
+    pub mileage: MileageCheck,
+}
+
+impl UploadReceipt {
+    /// Parses the body of an upload response.
+    ///
+    /// The run is recorded by then, so a field of an unexpected type is
+    /// logged and left out rather than failing the upload.
+    pub(crate) fn parse(body: &str, mileage: MileageCheck) -> Self {
+        let body = serde_json::from_str::<Value>(body).unwrap_or_else(|e| {
+            warn!("Unreadable upload response: {}", e);
+            Value::Null
+        });
+        let data = &body["data"];
+        let field = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| &data[*name])
+                .find(|value| !value.is_null())
+        };
+        let unexpected = |name: &str, value: &Value| {
+            warn!("Unexpected {} in upload response: {}", name, value);
+        };
+
+        let record_id = field(&["id", "recordId"]).and_then(|value| match value {
+            Value::String(id) => Some(id.clone()),
+            Value::Number(id) => Some(id.to_string()),
+            _ => {
+                unexpected("record id", value);
+                None
+            }
+        });
+        let effective_mileage = field(&["effectiveMileage"]).and_then(|value| {
+            let mileage = value.as_f64();
+            if mileage.is_none() {
+                unexpected("effective mileage", value);
+            }
+            mileage
+        });
+        let effective = field(&["effective", "isEffective"]).and_then(|value| {
+            let effective = value.as_bool();
+            if effective.is_none() {
+                unexpected("validity", value);
+            }
+            effective
+        });
+        let uneffective_reason = field(&["uneffectiveReason"]).and_then(|value| {
+            let reason = value.as_str();
+            if reason.is_none() {
+                unexpected("ineffective reason", value);
+            }
+            reason
+                .filter(|reason| !reason.is_empty())
+                .map(str::to_string)
+        });
+
+        Self {
+            record_id,
+            effective_mileage,
+            effective,
+            uneffective_reason,
+            mileage,
+        }
+    }
+
+    /// Whether the server accepted the run as counting, `true` unless it said
+    /// otherwise.
+    pub fn is_effective(&self) -> bool {
+        self.effective.unwrap_or(self.uneffective_reason.is_none())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RunningLimits;
+
+    #[test]
+    fn test_parse_receipt() {
+        let check = RunningLimits::default().check(0.);
+
+        let body = r#"{"code": 0, "data": {"id": "42", "effectiveMileage": 2.5, "isEffective": false, "uneffectiveReason": "Pace too fast"}}"#;
+        let receipt = UploadReceipt::parse(body, check.clone());
+        assert_eq!(receipt.record_id.as_deref(), Some("42"));
+        assert_eq!(receipt.effective_mileage, Some(2.5));
+        assert_eq!(receipt.uneffective_reason.as_deref(), Some("Pace too fast"));
+        assert!(!receipt.is_effective());
+
+        let body = r#"{"code": 0, "data": {"id": "43", "uneffectiveReason": ""}}"#;
+        let receipt = UploadReceipt::parse(body, check.clone());
+        assert!(receipt.uneffective_reason.is_none());
+        assert!(receipt.is_effective());
+
+        let receipt = UploadReceipt::parse(r#"{"code": 0, "data": null}"#, check.clone());
+        assert!(receipt.record_id.is_none());
+        assert!(receipt.is_effective());
+
+        let body =
+            r#"{"code": 0, "data": {"id": 42, "effectiveMileage": "2.5", "isEffective": true}}"#;
+        let receipt = UploadReceipt::parse(body, check.clone());
+        assert_eq!(receipt.record_id.as_deref(), Some("42"));
+        assert!(receipt.effective_mileage.is_none());
+        assert_eq!(receipt.effective, Some(true));
+
+        let receipt = UploadReceipt::parse(r#"{"recordId": "#, check);
+        assert!(receipt.record_id.is_none());
+    }
+}
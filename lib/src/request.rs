@@ -0,0 +1,308 @@
+/*
+    Pretty Der6y - A third-party running data upload client.
+    Copyright (C) 2024  Fay Ash
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published
+    by the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::str::FromStr;
+
+use chrono::{DateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{Checkpoint, Error, Result, Route, Track};
+
+/// Kind of run, serialized as the server names it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RunType {
+    /// A run anywhere, without checkpoints.
+    #[default]
+    #[serde(rename = "自由跑")]
+    Free,
+    /// A run that signs in at the checkpoints assigned by the school.
+    #[serde(rename = "定点跑")]
+    Checkpoint,
+}
+
+impl RunType {
+    /// The name the server uses for this run type.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RunType::Free => "自由跑",
+            RunType::Checkpoint => "定点跑",
+        }
+    }
+}
+
+impl FromStr for RunType {
+    type Err = Error;
+
+    /// Parses either the English or the server name of a run type.
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "free" | "自由跑" => Ok(RunType::Free),
+            "checkpoint" | "定点跑" => Ok(RunType::Checkpoint),
+            _ => Err(Error::Request(format!("Unknown run type: {s}"))),
+        }
+    }
+}
+
+/// Where the points and timing of an upload come from.
+#[derive(Clone, Debug)]
+pub(crate) enum Source {
+    /// A run generated along a route, ending at the given time.
+    Route {
+        route: Route,
+        distance: f64,
+        end_time: DateTime<Utc>,
+    },
+    /// A recorded track with its own distance and timing.
+    Track(Track),
+}
+
+/// A run to upload, see [`UploadRequest::builder`].
+#[derive(Clone, Debug)]
+pub struct UploadRequest {
+    pub(crate) source: Source,
+    pub(crate) run_type: RunType,
+    pub(crate) segments: u8,
+    pub(crate) checkpoints: Vec<Checkpoint>,
+    pub(crate) seed: Option<u64>,
+}
+
+impl UploadRequest {
+    pub fn builder() -> UploadRequestBuilder {
+        UploadRequestBuilder::default()
+    }
+
+    /// A run of `distance` kilometers along the route, ending now.
+    pub fn route(route: Route, distance: f64) -> Self {
+        Self::builder()
+            .route(route)
+            .distance(distance)
+            .build()
+            .expect("A route and a distance make a complete request")
+    }
+
+    /// A free run uploaded as recorded.
+    pub fn track(track: Track) -> Self {
+        Self::builder()
+            .track(track)
+            .build()
+            .expect("A track makes a complete request")
+    }
+
+    /// The checkpoints to sign in at, which only a checkpoint run has.
+    pub(crate) fn sign_points(&self) -> &[Checkpoint] {
+        match self.run_type {
+            RunType::Checkpoint => &self.checkpoints,
+            RunType::Free => &[],
+        }
+    }
+}
+
+/// Builds an [`UploadRequest`] from either a route or a recorded track.
+///
+/// A route needs the distance to run along it, while a track brings its own
+/// distance and timing.
+#[derive(Clone, Debug, Default)]
+pub struct UploadRequestBuilder {
+    route: Option<Route>,
+    track: Option<Track>,
+    distance: Option<f64>,
+    end_time: Option<DateTime<Utc>>,
+    run_type: Option<RunType>,
+    segments: Option<u8>,
+    checkpoints: Vec<Checkpoint>,
+    seed: Option<u64>,
+}
+
+impl UploadRequestBuilder {
+    /// Generates the run along a route.
+    pub fn route(mut self, route: Route) -> Self {
+        self.route = Some(route);
+        self
+    }
+
+    /// Uploads a recorded track.
+    pub fn track(mut self, track: Track) -> Self {
+        self.track = Some(track);
+        self
+    }
+
+    /// Sets the distance to run along the route, in kilometers.
+    pub fn distance(mut self, distance: f64) -> Self {
+        self.distance = Some(distance);
+        self
+    }
+
+    /// Sets when the run along the route ends, now by default.
+    ///
+    /// Any time zone will do, the times are sent in the account's
+    /// [`timezone`](crate::Account::timezone).
+    pub fn end_time<Z: TimeZone>(mut self, end_time: DateTime<Z>) -> Self {
+        self.end_time = Some(end_time.with_timezone(&Utc));
+        self
+    }
+
+    /// Sets the run type, by default a checkpoint run if there are any
+    /// checkpoints and a free run otherwise.
+    pub fn run_type(mut self, run_type: RunType) -> Self {
+        self.run_type = Some(run_type);
+        self
+    }
+
+    /// Sets the number of parts a run along the route is split into by
+    /// pauses, 1 by default. A track keeps its own segments.
+    pub fn segments(mut self, segments: u8) -> Self {
+        self.segments = Some(segments);
+        self
+    }
+
+    /// Adds a checkpoint the run passed, besides those of the route.
+    pub fn checkpoint(mut self, checkpoint: Checkpoint) -> Self {
+        self.checkpoints.push(checkpoint);
+        self
+    }
+
+    /// Adds several checkpoints the run passed.
+    pub fn checkpoints(mut self, checkpoints: impl IntoIterator<Item = Checkpoint>) -> Self {
+        self.checkpoints.extend(checkpoints);
+        self
+    }
+
+    /// Seeds the jitter of the generated line and timing, so the same request
+    /// builds the same payload every time.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn build(self) -> Result<UploadRequest> {
+        let mut checkpoints = Vec::new();
+        let source = match (self.route, self.track) {
+            (Some(route), None) => {
+                checkpoints.extend(route.checkpoints());
+                Source::Route {
+                    route,
+                    distance: self.distance.ok_or_else(|| {
+                        Error::Request("A route needs the distance to run".to_string())
+                    })?,
+                    end_time: self.end_time.unwrap_or_else(Utc::now),
+                }
+            }
+            (None, Some(track)) => {
+                if self.distance.is_some() || self.end_time.is_some() || self.segments.is_some() {
+                    return Err(Error::Request(
+                        "A track has its own distance, timing and segments".to_string(),
+                    ));
+                }
+                Source::Track(track)
+            }
+            (Some(_), Some(_)) => {
+                return Err(Error::Request(
+                    "Upload either a route or a track, not both".to_string(),
+                ))
+            }
+            (None, None) => return Err(Error::Request("Nothing to upload".to_string())),
+        };
+        checkpoints.extend(self.checkpoints);
+
+        let segments = self.segments.unwrap_or(1);
+        if segments == 0 {
+            return Err(Error::Request("A run has at least one segment".to_string()));
+        }
+
+        let run_type = self.run_type.unwrap_or(if checkpoints.is_empty() {
+            RunType::Free
+        } else {
+            RunType::Checkpoint
+        });
+        if run_type == RunType::Checkpoint && checkpoints.is_empty() {
+            return Err(Error::Request(
+                "A checkpoint run needs at least one checkpoint".to_string(),
+            ));
+        }
+
+        Ok(UploadRequest {
+            source,
+            run_type,
+            segments,
+            checkpoints,
+            seed: self.seed,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route() -> Route {
+        Route::from_geojson(include_str!("../../assets/map.geojson"), None).unwrap()
+    }
+
+    #[test]
+    fn test_build_route() {
+        let request = UploadRequest::builder()
+            .route(route())
+            .distance(3.)
+            .checkpoint(Checkpoint {
+                latitude: 30.5,
+                longitude: 114.3,
+                name: None,
+            })
+            .build()
+            .unwrap();
+        assert_eq!(request.run_type, RunType::Checkpoint);
+        assert_eq!(request.segments, 1);
+        assert_eq!(request.checkpoints.len(), 1);
+        assert!(matches!(request.source, Source::Route { distance, .. } if distance == 3.));
+    }
+
+    #[test]
+    fn test_build_invalid() {
+        let missing_distance = UploadRequest::builder().route(route()).build();
+        assert!(matches!(missing_distance, Err(Error::Request(_))));
+
+        assert!(matches!(
+            UploadRequest::builder().build(),
+            Err(Error::Request(_))
+        ));
+
+        let no_segments = UploadRequest::builder()
+            .route(route())
+            .distance(3.)
+            .segments(0)
+            .build();
+        assert!(matches!(no_segments, Err(Error::Request(_))));
+
+        let no_checkpoints = UploadRequest::builder()
+            .route(route())
+            .distance(3.)
+            .run_type(RunType::Checkpoint)
+            .build();
+        assert!(matches!(no_checkpoints, Err(Error::Request(_))));
+    }
+
+    #[test]
+    fn test_run_type_from_str() {
+        assert_eq!("free".parse::<RunType>().unwrap(), RunType::Free);
+        assert_eq!("定点跑".parse::<RunType>().unwrap(), RunType::Checkpoint);
+        assert!(matches!(
+            "sprint".parse::<RunType>(),
+            Err(Error::Request(_))
+        ));
+    }
+}
@@ -0,0 +1,141 @@
+/*
+    Pretty Der6y - A third-party running data upload client.
+    Copyright (C) 2024  Fay Ash
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published
+    by the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::time::Duration;
+
+use rand::{thread_rng, Rng};
+use reqwest::StatusCode;
+
+use crate::{Endpoint, Error};
+
+/// How [`Account`](crate::Account) times out and retries its requests.
+///
+/// Only the idempotent reads (current semester, version and running limits)
+/// are retried by default. Uploads are retried only if `retry_upload` is set,
+/// since a retried upload may be recorded twice.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Attempts per request, including the first one.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for every further retry.
+    pub base_delay: Duration,
+    /// Upper bound of the delay between two attempts.
+    pub max_delay: Duration,
+    /// Timeout for establishing a connection.
+    pub connect_timeout: Duration,
+    /// Timeout for a whole request, from sending it to reading the body.
+    pub timeout: Duration,
+    /// Whether uploads are retried as well.
+    pub retry_upload: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(8),
+            connect_timeout: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
+            retry_upload: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that never retries.
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn attempts(&self, endpoint: Endpoint) -> u32 {
+        let retryable = match endpoint {
+            Endpoint::Current | Endpoint::Version | Endpoint::RunningLimit => true,
+            Endpoint::Upload => self.retry_upload,
+            Endpoint::Login => false,
+        };
+
+        if retryable {
+            self.max_attempts.max(1)
+        } else {
+            1
+        }
+    }
+
+    /// The delay after the given failed attempt, counted from 1.
+    ///
+    /// Backs off exponentially, with the upper half of each delay jittered.
+    pub(crate) fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay);
+
+        let half = delay / 2;
+        half + half.mul_f64(thread_rng().gen::<f64>())
+    }
+}
+
+/// Whether a failed request may succeed when sent again.
+pub(crate) fn is_transient(e: &Error) -> bool {
+    match e {
+        Error::Network(_) => true,
+        Error::Status(status) => {
+            status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy {
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(300),
+            ..Default::default()
+        };
+
+        for (attempt, max) in [(1, 100), (2, 200), (3, 300), (10, 300)] {
+            let delay = policy.delay(attempt);
+            assert!(delay >= Duration::from_millis(max / 2));
+            assert!(delay <= Duration::from_millis(max));
+        }
+    }
+
+    #[test]
+    fn test_attempts() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.attempts(Endpoint::Current), 3);
+        assert_eq!(policy.attempts(Endpoint::Login), 1);
+        assert_eq!(policy.attempts(Endpoint::Upload), 1);
+
+        let policy = RetryPolicy {
+            retry_upload: true,
+            ..Default::default()
+        };
+        assert_eq!(policy.attempts(Endpoint::Upload), 3);
+        assert_eq!(RetryPolicy::none().attempts(Endpoint::Current), 1);
+    }
+}
@@ -17,9 +17,22 @@
 */
 
 use geo::{prelude::*, Point};
-use rand::{thread_rng, Rng};
+use rand::{thread_rng, Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 use serde::{Deserialize, Serialize};
-use std::{error::Error, f64::consts::PI};
+use std::{f64::consts::PI, fmt, str::FromStr};
+
+use crate::{kml, Error, Result, RoutineLine};
+
+fn out_of_china(lat: f64, lon: f64) -> bool {
+    if !(72.004..=137.8347).contains(&lon) {
+        return true;
+    }
+    if !(0.8293..=55.8271).contains(&lat) {
+        return true;
+    }
+    false
+}
 
 // WGS-84 to GCJ-02 (Mars Coordinate System) conversion
 // Only valid for coordinates within China.
@@ -43,16 +56,6 @@ fn wgs84_to_gcj02(lat: f64, lon: f64) -> (f64, f64) {
         ret
     }
 
-    fn out_of_china(lat: f64, lon: f64) -> bool {
-        if !(72.004..=137.8347).contains(&lon) {
-            return true;
-        }
-        if !(0.8293..=55.8271).contains(&lat) {
-            return true;
-        }
-        false
-    }
-
     if out_of_china(lat, lon) {
         return (lat, lon);
     }
@@ -60,61 +63,1176 @@ fn wgs84_to_gcj02(lat: f64, lon: f64) -> (f64, f64) {
     let mut d_lat = transform_lat(lon - 105.0, lat - 35.0);
     let mut d_lon = transform_lon(lon - 105.0, lat - 35.0);
     let rad_lat = lat / 180.0 * PI;
-    let magic = (1.0 - EE * rad_lat.sin() * rad_lat.sin()).sqrt();
-    d_lat = (d_lat * 180.0) / ((A * (1.0 - EE)) / (magic * magic) * PI);
-    d_lon = (d_lon * 180.0) / (A / magic * rad_lat.cos() * PI);
+    let magic = 1.0 - EE * rad_lat.sin() * rad_lat.sin();
+    let sqrt_magic = magic.sqrt();
+    d_lat = (d_lat * 180.0) / ((A * (1.0 - EE)) / (magic * sqrt_magic) * PI);
+    d_lon = (d_lon * 180.0) / (A / sqrt_magic * rad_lat.cos() * PI);
     let mg_lat = lat + d_lat;
     let mg_lon = lon + d_lon;
     (mg_lat, mg_lon)
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Finds the position that `forward` maps to the given one, by fixed-point
+/// iteration. Works for the offsets below, which are small and smooth.
+fn invert(forward: fn(f64, f64) -> (f64, f64), lat: f64, lon: f64) -> (f64, f64) {
+    let (mut x_lat, mut x_lon) = (lat, lon);
+    for _ in 0..16 {
+        let (f_lat, f_lon) = forward(x_lat, x_lon);
+        let (d_lat, d_lon) = (f_lat - lat, f_lon - lon);
+        x_lat -= d_lat;
+        x_lon -= d_lon;
+        if d_lat.abs() < 1e-12 && d_lon.abs() < 1e-12 {
+            break;
+        }
+    }
+    (x_lat, x_lon)
+}
+
+fn gcj02_to_wgs84(lat: f64, lon: f64) -> (f64, f64) {
+    if out_of_china(lat, lon) {
+        return (lat, lon);
+    }
+    invert(wgs84_to_gcj02, lat, lon)
+}
+
+const X_PI: f64 = PI * 3000. / 180.;
+
+// GCJ-02 to BD-09 (Baidu) conversion
+fn gcj02_to_bd09(lat: f64, lon: f64) -> (f64, f64) {
+    let z = lon.hypot(lat) + 0.00002 * (lat * X_PI).sin();
+    let theta = lat.atan2(lon) + 0.000003 * (lon * X_PI).cos();
+    (z * theta.sin() + 0.006, z * theta.cos() + 0.0065)
+}
+
+fn bd09_to_gcj02(lat: f64, lon: f64) -> (f64, f64) {
+    invert(gcj02_to_bd09, lat, lon)
+}
+
+/// The coordinate system of the positions in a route file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Crs {
+    /// GPS positions, as used by GPX, KML and most tools outside China.
+    #[default]
+    Wgs84,
+    /// The shifted positions of Amap and Tencent Maps, and of the server.
+    Gcj02,
+    /// The positions of Baidu Maps.
+    Bd09,
+}
+
+impl Crs {
+    /// Converts a position in this system to GCJ-02.
+    pub(crate) fn to_gcj02(self, lat: f64, lon: f64) -> (f64, f64) {
+        match self {
+            Crs::Wgs84 => wgs84_to_gcj02(lat, lon),
+            Crs::Gcj02 => (lat, lon),
+            Crs::Bd09 => bd09_to_gcj02(lat, lon),
+        }
+    }
+
+    /// Converts a GCJ-02 position to this system.
+    pub(crate) fn convert_gcj02(self, lat: f64, lon: f64) -> (f64, f64) {
+        match self {
+            Crs::Wgs84 => gcj02_to_wgs84(lat, lon),
+            Crs::Gcj02 => (lat, lon),
+            Crs::Bd09 => gcj02_to_bd09(lat, lon),
+        }
+    }
+
+    /// Converts a position in this system to WGS-84.
+    pub(crate) fn to_wgs84(self, lat: f64, lon: f64) -> (f64, f64) {
+        match self {
+            Crs::Wgs84 => (lat, lon),
+            Crs::Gcj02 => gcj02_to_wgs84(lat, lon),
+            Crs::Bd09 => {
+                let (lat, lon) = bd09_to_gcj02(lat, lon);
+                gcj02_to_wgs84(lat, lon)
+            }
+        }
+    }
+}
+
+impl FromStr for Crs {
+    type Err = Error;
+
+    /// Parses names like `gcj02`, `BD-09` or `EPSG:4326`, as well as the
+    /// `urn:ogc:def:crs:OGC:1.3:CRS84` of GeoJSON.
+    fn from_str(s: &str) -> Result<Self> {
+        let name: String = s
+            .chars()
+            .filter(|c| !matches!(c, '-' | '_' | ' '))
+            .collect::<String>()
+            .to_lowercase();
+
+        match name.as_str() {
+            "wgs84"
+            | "epsg:4326"
+            | "crs84"
+            | "urn:ogc:def:crs:ogc:1.3:crs84"
+            | "urn:ogc:def:crs:epsg::4326" => Ok(Crs::Wgs84),
+            "gcj02" => Ok(Crs::Gcj02),
+            "bd09" | "bd09ll" => Ok(Crs::Bd09),
+            _ => Err(Error::Route(format!("Unknown coordinate system: {}", s))),
+        }
+    }
+}
+
+impl fmt::Display for Crs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Crs::Wgs84 => "WGS-84",
+            Crs::Gcj02 => "GCJ-02",
+            Crs::Bd09 => "BD-09",
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct LGPoint {
-    longitude: f64,
-    latitude: f64,
+    pub(crate) longitude: f64,
+    pub(crate) latitude: f64,
 }
 
-pub fn get_routine(mut mileage: f64, geojson_str: &str) -> Result<Vec<LGPoint>, Box<dyn Error>> {
-    let mut points = Vec::new();
-    let mut last = None;
-    let mut rng = thread_rng();
-    let geo_json: geojson::GeoJson = geojson_str.parse()?;
-    let features = match geo_json {
-        geojson::GeoJson::FeatureCollection(fc) => fc.features,
-        _ => return Err("Invalid GeoJSON".into()),
+impl LGPoint {
+    /// Converts a WGS-84 position to the GCJ-02 point sent to the server.
+    pub(crate) fn from_wgs84(latitude: f64, longitude: f64) -> Self {
+        let (latitude, longitude) = wgs84_to_gcj02(latitude, longitude);
+        Self {
+            longitude,
+            latitude,
+        }
+    }
+}
+
+/// Picks one route out of a file that contains several.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RouteSelector {
+    /// The n-th route in the file, counted from 0.
+    Index(usize),
+    /// The route whose feature or placemark has this name.
+    Name(String),
+}
+
+/// A route to run along, as longitude and latitude pairs in the coordinate
+/// system of its file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Route {
+    coordinates: Vec<Vec<f64>>,
+    checkpoints: Vec<Checkpoint>,
+    crs: Crs,
+}
+
+/// How close a run has to pass a checkpoint, in meters.
+pub const CHECKPOINT_RADIUS: f64 = 50.;
+
+/// How far in kilometers the length of a generated line may be from the
+/// requested mileage, due to the jitter of its points. The mileage uploaded
+/// with the line is capped at the requested one.
+pub const ROUTINE_TOLERANCE: f64 = 0.01;
+
+/// Shortest lap in meters a run can be generated along.
+pub const MIN_LAP_LENGTH: f64 = 1.;
+
+/// Most points a generated line may have.
+pub const MAX_ROUTINE_POINTS: usize = 100_000;
+
+/// Length in meters of a lap along `points` and back to the first one.
+fn lap_length(points: &[Point]) -> f64 {
+    let closure = match (points.first(), points.last()) {
+        (Some(first), Some(last)) => last.geodesic_distance(first),
+        _ => 0.,
     };
+    points
+        .windows(2)
+        .map(|pair| pair[0].geodesic_distance(&pair[1]))
+        .sum::<f64>()
+        + closure
+}
 
-    let feature = features.first().ok_or("No feature found")?;
-    let geometry = feature.geometry.as_ref().ok_or("No geometry found")?;
-    let coordinates = match geometry.value {
-        geojson::Value::LineString(ref ls) => ls,
-        _ => return Err("Invalid geometry".into()),
+/// A point the run has to pass and sign in at, in WGS-84.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Name of the feature or placemark that marks it.
+    pub name: Option<String>,
+}
+
+impl Checkpoint {
+    fn describe(&self, index: usize) -> String {
+        match &self.name {
+            Some(name) => format!("Checkpoint {} ({})", index, name),
+            None => format!("Checkpoint {}", index),
+        }
+    }
+}
+
+impl From<&Checkpoint> for LGPoint {
+    fn from(checkpoint: &Checkpoint) -> Self {
+        LGPoint::from_wgs84(checkpoint.latitude, checkpoint.longitude)
+    }
+}
+
+/// The extent of a route, in WGS-84.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BoundingBox {
+    pub min_longitude: f64,
+    pub min_latitude: f64,
+    pub max_longitude: f64,
+    pub max_latitude: f64,
+}
+
+/// Statistics of a route, to check that it is usable before uploading.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RouteInfo {
+    /// Geodesic length of one lap in kilometers, back to the first point.
+    pub length: f64,
+    pub points: usize,
+    pub bounding_box: BoundingBox,
+    /// Longest distance between two consecutive points in meters.
+    pub max_segment: f64,
+    /// Distance from the last point back to the first in meters.
+    pub closure_gap: f64,
+    /// Whether every point lies in the region where GCJ-02 offsets apply.
+    pub in_china: bool,
+    /// Indices of the points that repeat the point before them.
+    pub duplicate_points: Vec<usize>,
+    /// Number of checkpoints marked in the route file.
+    pub checkpoints: usize,
+    /// Coordinate system the route file is read in.
+    pub crs: Crs,
+    /// Problems that may make the route unusable.
+    pub warnings: Vec<String>,
+}
+
+/// A line found in a route file, named after its feature or placemark.
+pub(crate) struct Candidate {
+    pub(crate) name: Option<String>,
+    /// Longitude and latitude pairs, in WGS-84.
+    pub(crate) coordinates: Vec<Vec<f64>>,
+}
+
+/// The lines and checkpoints found in a route file, and the coordinate
+/// system it declares.
+#[derive(Default)]
+pub(crate) struct Features {
+    pub(crate) candidates: Vec<Candidate>,
+    pub(crate) checkpoints: Vec<Checkpoint>,
+    pub(crate) crs: Option<Crs>,
+}
+
+impl Features {
+    /// Reads a `crs` member or property, either a name or a GeoJSON named
+    /// CRS object.
+    fn declare(&mut self, member: Option<&serde_json::Value>) -> Result<()> {
+        let name = match member {
+            None | Some(serde_json::Value::Null) => return Ok(()),
+            Some(serde_json::Value::String(name)) => name.as_str(),
+            Some(value) => value["properties"]["name"]
+                .as_str()
+                .ok_or_else(|| Error::Route(format!("Unsupported coordinate system: {}", value)))?,
+        };
+
+        let crs = name.parse()?;
+        match self.crs {
+            Some(declared) if declared != crs => Err(Error::Route(format!(
+                "Conflicting coordinate systems: {} and {}",
+                declared, crs
+            ))),
+            _ => {
+                self.crs = Some(crs);
+                Ok(())
+            }
+        }
+    }
+
+    /// Adds the lines of a geometry, and its points as well if they are
+    /// marked as checkpoints.
+    fn add_geometry(
+        &mut self,
+        name: Option<String>,
+        geometry: &geojson::Geometry,
+        checkpoint: bool,
+    ) {
+        let to_checkpoint = |position: &Vec<f64>| Checkpoint {
+            latitude: position[1],
+            longitude: position[0],
+            name: name.clone(),
+        };
+
+        match geometry.value {
+            geojson::Value::LineString(ref ls) => self.candidates.push(Candidate {
+                name,
+                coordinates: ls.clone(),
+            }),
+            geojson::Value::MultiLineString(ref mls) => self.candidates.push(Candidate {
+                name,
+                coordinates: mls.concat(),
+            }),
+            geojson::Value::Point(ref point) if checkpoint => {
+                self.checkpoints.push(to_checkpoint(point))
+            }
+            geojson::Value::MultiPoint(ref points) if checkpoint => {
+                self.checkpoints.extend(points.iter().map(to_checkpoint))
+            }
+            _ => {}
+        }
+    }
+
+    fn add_feature(&mut self, feature: &geojson::Feature) -> Result<()> {
+        self.declare(feature.property("crs"))?;
+        self.declare(feature.foreign_members.as_ref().and_then(|m| m.get("crs")))?;
+
+        let name = feature
+            .property("name")
+            .and_then(|name| name.as_str())
+            .map(str::to_string);
+        let checkpoint = feature
+            .property("checkpoint")
+            .and_then(|checkpoint| checkpoint.as_bool())
+            .unwrap_or(false);
+        if let Some(geometry) = &feature.geometry {
+            self.declare(geometry.foreign_members.as_ref().and_then(|m| m.get("crs")))?;
+            self.add_geometry(name, geometry, checkpoint);
+        }
+        Ok(())
+    }
+}
+
+/// Distance from a point to the closest part of a line, in meters, with
+/// both given as longitude and latitude pairs.
+///
+/// Uses an equirectangular projection around the point, which is accurate
+/// enough at the scale of a checkpoint.
+fn distance_to_line(point: (f64, f64), line: impl IntoIterator<Item = (f64, f64)>) -> f64 {
+    const EARTH_RADIUS: f64 = 6_371_008.8;
+
+    let scale = point.1.to_radians().cos();
+    let project = |(lon, lat): (f64, f64)| {
+        (
+            (lon - point.0).to_radians() * scale * EARTH_RADIUS,
+            (lat - point.1).to_radians() * EARTH_RADIUS,
+        )
+    };
+
+    let points: Vec<_> = line.into_iter().map(project).collect();
+    let distance_to_segment = |(ax, ay): (f64, f64), (bx, by): (f64, f64)| {
+        let (dx, dy) = (bx - ax, by - ay);
+        let length = dx * dx + dy * dy;
+        let t = if length == 0. {
+            0.
+        } else {
+            (-(ax * dx + ay * dy) / length).clamp(0., 1.)
+        };
+        (ax + t * dx).hypot(ay + t * dy)
     };
 
-    if coordinates.is_empty() {
-        return Err("No coordinates found".into());
+    match points.as_slice() {
+        [] => f64::INFINITY,
+        [only] => only.0.hypot(only.1),
+        _ => points
+            .windows(2)
+            .map(|pair| distance_to_segment(pair[0], pair[1]))
+            .fold(f64::INFINITY, f64::min),
+    }
+}
+
+/// Makes sure one of the segments of a run passes within
+/// [`CHECKPOINT_RADIUS`] of every checkpoint.
+pub(crate) fn check_checkpoints(
+    segments: &[Vec<LGPoint>],
+    checkpoints: &[Checkpoint],
+) -> Result<()> {
+    for (index, checkpoint) in checkpoints.iter().enumerate() {
+        let target = LGPoint::from(checkpoint);
+        let distance = segments
+            .iter()
+            .map(|segment| {
+                distance_to_line(
+                    (target.longitude, target.latitude),
+                    segment
+                        .iter()
+                        .map(|point| (point.longitude, point.latitude)),
+                )
+            })
+            .fold(f64::INFINITY, f64::min);
+        if distance > CHECKPOINT_RADIUS {
+            return Err(Error::Route(format!(
+                "{} is {:.0} m away from the run, at most {:.0} m allowed",
+                checkpoint.describe(index),
+                distance,
+                CHECKPOINT_RADIUS
+            )));
+        }
+    }
+    Ok(())
+}
+
+impl Route {
+    /// Reads a `LineString` or `MultiLineString` of a GeoJSON document, which
+    /// may be a `FeatureCollection`, a `Feature` or a bare geometry.
+    ///
+    /// The segments of a `MultiLineString` are joined in order. Features are
+    /// named by their `name` property, and a selector is required if the
+    /// document has more than one line. `Point` and `MultiPoint` features
+    /// with a `"checkpoint": true` property become the checkpoints of the
+    /// route, other points are ignored.
+    ///
+    /// Positions are read in WGS-84, unless the document or one of its
+    /// features declares another system in a `crs` member or property, e.g.
+    /// `"crs": "gcj02"`.
+    pub fn from_geojson(geojson_str: &str, selector: Option<&RouteSelector>) -> Result<Self> {
+        let geo_json: geojson::GeoJson = geojson_str.parse()?;
+        let mut features = Features::default();
+        match geo_json {
+            geojson::GeoJson::FeatureCollection(fc) => {
+                features.declare(fc.foreign_members.as_ref().and_then(|m| m.get("crs")))?;
+                for feature in &fc.features {
+                    features.add_feature(feature)?;
+                }
+            }
+            geojson::GeoJson::Feature(feature) => features.add_feature(&feature)?,
+            geojson::GeoJson::Geometry(geometry) => {
+                features.declare(geometry.foreign_members.as_ref().and_then(|m| m.get("crs")))?;
+                features.add_geometry(None, &geometry, false);
+            }
+        };
+
+        Self::select(features, selector, "LineString or MultiLineString")
+    }
+
+    /// Reads a `LineString` or `gx:Track` placemark of a KML document.
+    ///
+    /// A selector is required if the document has more than one of them.
+    /// `Point` placemarks with a `checkpoint` data field set to `true` become
+    /// the checkpoints of the route, other points are ignored.
+    pub fn from_kml(kml_str: &str, selector: Option<&RouteSelector>) -> Result<Self> {
+        Self::select(kml::parse(kml_str)?, selector, "LineString or gx:Track")
+    }
+
+    fn select(features: Features, selector: Option<&RouteSelector>, kind: &str) -> Result<Self> {
+        let Features {
+            mut candidates,
+            checkpoints,
+            crs,
+        } = features;
+        let index = match selector {
+            Some(RouteSelector::Index(index)) => {
+                if *index >= candidates.len() {
+                    return Err(Error::Route(format!(
+                        "Route index {} out of range, found {} routes",
+                        index,
+                        candidates.len()
+                    )));
+                }
+                *index
+            }
+            Some(RouteSelector::Name(name)) => candidates
+                .iter()
+                .position(|candidate| candidate.name.as_ref() == Some(name))
+                .ok_or_else(|| Error::Route(format!("No route named {}", name)))?,
+            None => match candidates.len() {
+                0 => return Err(Error::Route(format!("No {} found", kind))),
+                1 => 0,
+                _ => {
+                    let names = candidates
+                        .iter()
+                        .enumerate()
+                        .map(|(index, candidate)| match &candidate.name {
+                            Some(name) => format!("{}: {}", index, name),
+                            None => index.to_string(),
+                        })
+                        .collect::<Vec<_>>();
+                    return Err(Error::Route(format!(
+                        "Found {} routes ({}), select one by index or name",
+                        candidates.len(),
+                        names.join(", ")
+                    )));
+                }
+            },
+        };
+
+        let mut route = Self::new(candidates.swap_remove(index).coordinates)?;
+        route.checkpoints = checkpoints;
+        route.crs = crs.unwrap_or_default();
+        Ok(route)
+    }
+
+    /// The checkpoints marked in the route file, in the order they appear,
+    /// converted to WGS-84.
+    pub fn checkpoints(&self) -> Vec<Checkpoint> {
+        self.checkpoints
+            .iter()
+            .map(|checkpoint| {
+                let (latitude, longitude) =
+                    self.crs.to_wgs84(checkpoint.latitude, checkpoint.longitude);
+                Checkpoint {
+                    latitude,
+                    longitude,
+                    name: checkpoint.name.clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// Generates the line of a run of `mileage` kilometers along the route,
+    /// split into `segments` parts, the same way an upload does.
+    pub fn routine(&self, mileage: f64, segments: u8) -> Result<RoutineLine> {
+        self.routine_with(mileage, segments, &mut thread_rng())
+    }
+
+    /// Same as [`Route::routine`], with the jitter seeded so the same seed
+    /// gives the same line. An upload seeded with the same
+    /// [`seed`](crate::UploadRequestBuilder::seed) jitters its line the same
+    /// way, up to where its slightly shorter run ends.
+    pub fn routine_seeded(&self, mileage: f64, segments: u8, seed: u64) -> Result<RoutineLine> {
+        self.routine_with(mileage, segments, &mut line_rng(seed))
     }
 
+    /// Same as [`Route::routine`], drawing the jitter from `rng`.
+    pub fn routine_with<R: Rng + ?Sized>(
+        &self,
+        mileage: f64,
+        segments: u8,
+        rng: &mut R,
+    ) -> Result<RoutineLine> {
+        if segments == 0 {
+            return Err(Error::Request("A run has at least one segment".to_string()));
+        }
+        Ok(RoutineLine::new(split_routine(
+            get_routine(mileage, self, rng)?,
+            segments.into(),
+        )))
+    }
+
+    /// The coordinate system the route is read in.
+    pub fn crs(&self) -> Crs {
+        self.crs
+    }
+
+    /// Reads the route in another coordinate system than the one its file
+    /// declares, e.g. for a KML file exported from a Chinese map.
+    pub fn with_crs(mut self, crs: Crs) -> Self {
+        self.crs = crs;
+        self
+    }
+
+    /// Reads the KML document of a zipped KMZ archive, see [`Route::from_kml`].
+    pub fn from_kmz(kmz: &[u8], selector: Option<&RouteSelector>) -> Result<Self> {
+        Self::from_kml(&kml::unzip(kmz)?, selector)
+    }
+
+    /// Measures the route and reports what may keep it from being uploaded.
+    pub fn info(&self) -> RouteInfo {
+        let points: Vec<_> = self
+            .coordinates
+            .iter()
+            .map(|coord| {
+                let (lat, lon) = self.crs.to_wgs84(coord[1], coord[0]);
+                Point::new(lon, lat)
+            })
+            .collect();
+
+        let segments: Vec<_> = points
+            .windows(2)
+            .map(|pair| pair[0].geodesic_distance(&pair[1]))
+            .collect();
+        let duplicate_points: Vec<_> = points
+            .windows(2)
+            .enumerate()
+            .filter(|(_, pair)| pair[0] == pair[1])
+            .map(|(index, _)| index + 1)
+            .collect();
+
+        let bounding_box = points.iter().fold(
+            BoundingBox {
+                min_longitude: f64::INFINITY,
+                min_latitude: f64::INFINITY,
+                max_longitude: f64::NEG_INFINITY,
+                max_latitude: f64::NEG_INFINITY,
+            },
+            |bbox, point| BoundingBox {
+                min_longitude: bbox.min_longitude.min(point.x()),
+                min_latitude: bbox.min_latitude.min(point.y()),
+                max_longitude: bbox.max_longitude.max(point.x()),
+                max_latitude: bbox.max_latitude.max(point.y()),
+            },
+        );
+
+        let in_china = points
+            .iter()
+            .all(|point| !out_of_china(point.y(), point.x()));
+        let lap = lap_length(&points);
+        let length = lap / 1000.;
+
+        let mut warnings = Vec::new();
+        if lap < MIN_LAP_LENGTH {
+            warnings.push(format!(
+                "Route is only {:.2} m per lap, too short to run along",
+                lap
+            ));
+        }
+        if !duplicate_points.is_empty() {
+            warnings.push(format!(
+                "{} duplicate consecutive points",
+                duplicate_points.len()
+            ));
+        }
+        if !in_china {
+            warnings.push("Route lies partly outside China".to_string());
+        }
+        for (index, checkpoint) in self.checkpoints().iter().enumerate() {
+            let distance = distance_to_line(
+                (checkpoint.longitude, checkpoint.latitude),
+                points.iter().map(|point| (point.x(), point.y())),
+            );
+            if distance > CHECKPOINT_RADIUS {
+                warnings.push(format!(
+                    "{} is {:.0} m away from the route",
+                    checkpoint.describe(index),
+                    distance
+                ));
+            }
+        }
+
+        RouteInfo {
+            length,
+            points: points.len(),
+            bounding_box,
+            max_segment: segments.iter().copied().fold(0., f64::max),
+            closure_gap: points[0].geodesic_distance(&points[points.len() - 1]),
+            in_china,
+            duplicate_points,
+            checkpoints: self.checkpoints.len(),
+            crs: self.crs,
+            warnings,
+        }
+    }
+
+    fn new(coordinates: Vec<Vec<f64>>) -> Result<Self> {
+        if coordinates.is_empty() {
+            return Err(Error::Route("No coordinates found".to_string()));
+        }
+
+        Ok(Self {
+            coordinates,
+            checkpoints: Vec::new(),
+            crs: Crs::default(),
+        })
+    }
+}
+
+/// The seeded source of the jitter of a line, apart from the one of the
+/// timing of a run.
+pub(crate) fn line_rng(seed: u64) -> ChaCha8Rng {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    rng.set_stream(1);
+    rng
+}
+
+/// Generates a line of `mileage` kilometers along the route, drawing the
+/// jitter from `rng`, so a seeded generator gives the same line every time.
+///
+/// The last segment is cut where the distance is reached, so the line is as
+/// long as requested within [`ROUTINE_TOLERANCE`].
+///
+/// Fails with [`Error::DegenerateRoute`] if a lap is shorter than
+/// [`MIN_LAP_LENGTH`], and with [`Error::TooManyPoints`] if the line would
+/// take more than [`MAX_ROUTINE_POINTS`].
+pub fn get_routine<R: Rng + ?Sized>(
+    mut mileage: f64,
+    route: &Route,
+    rng: &mut R,
+) -> Result<Vec<LGPoint>> {
+    if !mileage.is_finite() {
+        return Err(Error::Request(format!("Invalid mileage: {}", mileage)));
+    }
+
+    let lap: Vec<_> = route
+        .coordinates
+        .iter()
+        .map(|coord| {
+            let (y, x) = route.crs.to_gcj02(coord[1], coord[0]);
+            Point::new(x, y)
+        })
+        .collect();
+    let length = lap_length(&lap);
+    if length < MIN_LAP_LENGTH {
+        return Err(Error::DegenerateRoute(length));
+    }
+    let laps = (mileage.max(0.) * 1000. / length).ceil();
+    if laps * lap.len() as f64 > MAX_ROUTINE_POINTS as f64 {
+        return Err(Error::TooManyPoints(MAX_ROUTINE_POINTS));
+    }
+
+    let mut points = Vec::new();
+    let mut last: Option<Point> = None;
+    let mut jitter = |point: Point| LGPoint {
+        longitude: point.x() + rng.gen_range(-5e-6..5e-6),
+        latitude: point.y() + rng.gen_range(-5e-6..5e-6),
+    };
+
     loop {
-        for coord in coordinates {
-            let (y, x) = wgs84_to_gcj02(coord[1], coord[0]);
-            let point = Point::new(x, y);
-            if last.is_none() {
-                last = Some(point);
+        for &point in &lap {
+            if points.len() >= MAX_ROUTINE_POINTS {
+                return Err(Error::TooManyPoints(MAX_ROUTINE_POINTS));
             }
 
-            let new = LGPoint {
-                longitude: point.x() + rng.gen_range(-5e-6..5e-6),
-                latitude: point.y() + rng.gen_range(-5e-6..5e-6),
+            let Some(from) = last else {
+                points.push(jitter(point));
+                last = Some(point);
+                if mileage <= 0. {
+                    return Ok(points);
+                }
+                continue;
             };
-            mileage -= last.unwrap().geodesic_distance(&point) / 1000.;
+
+            let distance = from.geodesic_distance(&point) / 1000.;
+            if distance >= mileage {
+                let end = from.geodesic_intermediate(&point, mileage / distance);
+                points.push(jitter(end));
+                return Ok(points);
+            }
+
+            mileage -= distance;
+            points.push(jitter(point));
             last = Some(point);
+        }
+    }
+}
 
-            points.push(new);
+/// Joins the segments of a run into the line sent to the server, without
+/// repeating the point a segment starts at if the one before ended there.
+pub(crate) fn join_segments(segments: &[Vec<LGPoint>]) -> Vec<LGPoint> {
+    let mut line: Vec<LGPoint> = Vec::new();
+    for segment in segments {
+        let skip = match (line.last(), segment.first()) {
+            (Some(last), Some(first)) => usize::from(last == first),
+            _ => 0,
+        };
+        line.extend(segment[skip..].iter().cloned());
+    }
+    line
+}
 
-            if mileage <= 0. {
-                return Ok(points);
+/// Splits a generated line into `parts` segments of about the same length,
+/// each starting where the one before it paused.
+pub(crate) fn split_routine(line: Vec<LGPoint>, parts: usize) -> Vec<Vec<LGPoint>> {
+    let position = |point: &LGPoint| Point::new(point.longitude, point.latitude);
+    let mut travelled = vec![0.];
+    for pair in line.windows(2) {
+        let last = travelled[travelled.len() - 1];
+        travelled.push(last + position(&pair[0]).geodesic_distance(&position(&pair[1])));
+    }
+    let length = travelled[travelled.len() - 1];
+
+    let mut segments = Vec::with_capacity(parts);
+    let mut start = 0;
+    for part in 1..=parts {
+        let end = if part == parts {
+            line.len() - 1
+        } else {
+            let target = length * part as f64 / parts as f64;
+            travelled
+                .partition_point(|&distance| distance < target)
+                .max(start)
+        };
+        segments.push(line[start..=end].to_vec());
+        start = end;
+    }
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kml::tests::{zip, KML};
+    use crate::tests::assert_golden;
+
+    #[test]
+    fn test_get_routine_truncate() {
+        // A single straight kilometer, out and back.
+        let route = Route::from_geojson(
+            r#"{"type": "LineString", "coordinates": [[114.3, 30.5], [114.31, 30.5]]}"#,
+            None,
+        )
+        .unwrap();
+        let length = Point::new(114.3, 30.5).geodesic_distance(&Point::new(114.31, 30.5)) / 1000.;
+
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+        for mileage in [0.25, 0.5 * length, 1.5, 2.0 * length + 0.1] {
+            let line = route.routine_with(mileage, 1, &mut rng).unwrap();
+            assert!(
+                (line.length() - mileage).abs() < ROUTINE_TOLERANCE,
+                "{} km for {} km",
+                line.length(),
+                mileage
+            );
+        }
+
+        // The cut lies on the segment, not at its end.
+        let line = get_routine(0.25, &route, &mut rng).unwrap();
+        assert_eq!(line.len(), 2);
+        let travelled = line[1].longitude - line[0].longitude;
+        assert!((travelled - 0.01 * 0.25 / length).abs() < 2e-5);
+
+        let route = Route::from_geojson(include_str!("../../assets/map.geojson"), None).unwrap();
+        for mileage in [0.8, 3.0, 7.5] {
+            let line = route.routine_with(mileage, 2, &mut rng).unwrap();
+            assert!((line.length() - mileage).abs() < ROUTINE_TOLERANCE);
+        }
+    }
+
+    #[test]
+    fn test_get_routine_degenerate() {
+        let line = |coordinates: &str| {
+            Route::from_geojson(
+                &format!(
+                    r#"{{"type": "LineString", "coordinates": {}}}"#,
+                    coordinates
+                ),
+                None,
+            )
+            .unwrap()
+        };
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+
+        let single = line("[[114.3, 30.5]]");
+        let duplicate = line("[[114.3, 30.5], [114.3, 30.5], [114.3, 30.5]]");
+        let sub_meter = line("[[114.3, 30.5], [114.300003, 30.5]]");
+        for route in [&single, &duplicate, &sub_meter] {
+            assert!(matches!(
+                get_routine(3.0, route, &mut rng),
+                Err(Error::DegenerateRoute(length)) if length < MIN_LAP_LENGTH
+            ));
+            assert!(matches!(
+                route.routine(3.0, 1),
+                Err(Error::DegenerateRoute(_))
+            ));
+        }
+        assert!(sub_meter.info().warnings[0].contains("too short"));
+
+        // Long enough to run along, but not for hundreds of kilometers.
+        let short = line("[[114.3, 30.5], [114.30001, 30.5]]");
+        assert!(get_routine(0.1, &short, &mut rng).is_ok());
+        assert!(matches!(
+            get_routine(200.0, &short, &mut rng),
+            Err(Error::TooManyPoints(MAX_ROUTINE_POINTS))
+        ));
+
+        assert!(matches!(
+            get_routine(f64::NAN, &short, &mut rng),
+            Err(Error::Request(_))
+        ));
+    }
+
+    #[test]
+    fn test_get_routine_seed() {
+        let route = Route::from_geojson(include_str!("../../assets/map.geojson"), None).unwrap();
+        let routine = |seed| get_routine(3.0, &route, &mut ChaCha8Rng::seed_from_u64(seed));
+
+        let line = serde_json::to_string_pretty(&routine(42).unwrap()).unwrap();
+        assert_golden("routine.json", &line);
+        assert_eq!(routine(42).unwrap(), routine(42).unwrap());
+        assert_ne!(routine(42).unwrap(), routine(43).unwrap());
+    }
+
+    #[test]
+    fn test_route_from_kml() {
+        let result = Route::from_kml(KML, None);
+        let Err(Error::Route(message)) = result else {
+            panic!("expected a route error, got {:?}", result);
+        };
+        assert!(message.contains("0: Playground, 1: Lake"));
+
+        let route = Route::from_kml(KML, Some(&RouteSelector::Name("Lake".to_string()))).unwrap();
+        assert_eq!(route.coordinates[0], vec![114.31, 30.51]);
+
+        let route = Route::from_kml(KML, Some(&RouteSelector::Index(0))).unwrap();
+        assert_eq!(route.coordinates.len(), 3);
+
+        assert!(Route::from_kml(KML, Some(&RouteSelector::Index(2))).is_err());
+        assert!(Route::from_kml(KML, Some(&RouteSelector::Name("Gym".to_string()))).is_err());
+        assert!(Route::from_kml("<kml></kml>", None).is_err());
+    }
+
+    #[test]
+    fn test_route_from_kmz() {
+        let kmz = zip("doc.kml", KML);
+        let route = Route::from_kmz(&kmz, Some(&RouteSelector::Index(1))).unwrap();
+        assert_eq!(route.coordinates.len(), 2);
+    }
+
+    #[test]
+    fn test_route_from_geojson() {
+        let route = Route::from_geojson(include_str!("../../assets/map.geojson"), None).unwrap();
+        assert!(!route.coordinates.is_empty());
+
+        let geometry = r#"{"type": "MultiLineString", "coordinates": [[[114.3, 30.5], [114.31, 30.5]], [[114.31, 30.51], [114.3, 30.51]]]}"#;
+        let route = Route::from_geojson(geometry, None).unwrap();
+        assert_eq!(
+            route.coordinates,
+            vec![
+                vec![114.3, 30.5],
+                vec![114.31, 30.5],
+                vec![114.31, 30.51],
+                vec![114.3, 30.51]
+            ]
+        );
+
+        let feature = format!(
+            r#"{{"type": "Feature", "properties": {{"name": "Lap"}}, "geometry": {}}}"#,
+            geometry
+        );
+        let route = Route::from_geojson(&feature, None).unwrap();
+        assert_eq!(route.coordinates.len(), 4);
+
+        let point = r#"{"type": "Point", "coordinates": [114.3, 30.5]}"#;
+        assert!(matches!(
+            Route::from_geojson(point, None),
+            Err(Error::Route(_))
+        ));
+    }
+
+    #[test]
+    fn test_route_from_geojson_selection() {
+        let collection = r#"{"type": "FeatureCollection", "features": [
+            {"type": "Feature", "properties": {"name": "Gate"}, "geometry": {"type": "Point", "coordinates": [114.3, 30.5]}},
+            {"type": "Feature", "properties": {"name": "Track"}, "geometry": {"type": "LineString", "coordinates": [[114.3, 30.5], [114.31, 30.5]]}},
+            {"type": "Feature", "properties": null, "geometry": {"type": "LineString", "coordinates": [[114.4, 30.6], [114.41, 30.6]]}}
+        ]}"#;
+
+        let result = Route::from_geojson(collection, None);
+        let Err(Error::Route(message)) = result else {
+            panic!("expected a route error, got {:?}", result);
+        };
+        assert!(message.contains("0: Track, 1"));
+
+        let route =
+            Route::from_geojson(collection, Some(&RouteSelector::Name("Track".to_string())))
+                .unwrap();
+        assert_eq!(route.coordinates[0], vec![114.3, 30.5]);
+
+        let route = Route::from_geojson(collection, Some(&RouteSelector::Index(1))).unwrap();
+        assert_eq!(route.coordinates[0], vec![114.4, 30.6]);
+
+        assert!(
+            Route::from_geojson(collection, Some(&RouteSelector::Name("Gate".to_string())))
+                .is_err()
+        );
+    }
+
+    fn assert_close((lat, lon): (f64, f64), (expected_lat, expected_lon): (f64, f64), eps: f64) {
+        assert!(
+            (lat - expected_lat).abs() < eps && (lon - expected_lon).abs() < eps,
+            "({}, {}) is not ({}, {})",
+            lat,
+            lon,
+            expected_lat,
+            expected_lon
+        );
+    }
+
+    #[test]
+    fn test_wgs84_to_gcj02_reference() {
+        // Published WGS-84 to GCJ-02 pairs of the coordtransform and
+        // eviltransform libraries. The latitude offset divides by
+        // `magic * sqrt_magic`, the older `magic * magic` is off by about
+        // 2e-6 degrees.
+        for (wgs84, gcj02) in [
+            ((39.915, 116.404), (39.91640428150164, 116.41024449916938)),
+            (
+                (31.1774276, 121.5272106),
+                (31.17530398364597, 121.531541859215),
+            ),
+            (
+                (22.543847, 113.912316),
+                (22.540796131694766, 113.9171764808363),
+            ),
+            (
+                (39.911954, 116.377817),
+                (39.91334545536069, 116.38404722455657),
+            ),
+        ] {
+            assert_close(wgs84_to_gcj02(wgs84.0, wgs84.1), gcj02, 1e-9);
+        }
+
+        // Outside China positions are left alone.
+        assert_eq!(wgs84_to_gcj02(48.8566, 2.3522), (48.8566, 2.3522));
+    }
+
+    #[test]
+    fn test_transform_reference() {
+        // Reference values of the widely used coordtransform library, whose
+        // inverse conversions are one-step approximations.
+        let (lat, lon) = (39.915, 116.404);
+        assert_close(
+            gcj02_to_bd09(lat, lon),
+            (39.92133699351021, 116.41036949371029),
+            1e-9,
+        );
+        assert_close(
+            gcj02_to_wgs84(lat, lon),
+            (39.91359571849836, 116.39775550083061),
+            2e-5,
+        );
+        assert_close(
+            bd09_to_gcj02(lat, lon),
+            (39.90865673957631, 116.39762729119315),
+            2e-5,
+        );
+    }
+
+    #[test]
+    fn test_transform_round_trip() {
+        for (lat, lon) in [
+            (39.915, 116.404),
+            (30.5, 114.3),
+            (22.54, 114.06),
+            (45.75, 126.63),
+        ] {
+            for crs in [Crs::Wgs84, Crs::Gcj02, Crs::Bd09] {
+                let (gcj_lat, gcj_lon) = Crs::Wgs84.to_gcj02(lat, lon);
+                let (wgs_lat, wgs_lon) = Crs::Gcj02.to_wgs84(gcj_lat, gcj_lon);
+                // Within a millimeter.
+                assert_close((wgs_lat, wgs_lon), (lat, lon), 1e-8);
+
+                let position = crs.to_gcj02(lat, lon);
+                let wgs84 = crs.to_wgs84(lat, lon);
+                assert_close(Crs::Wgs84.to_gcj02(wgs84.0, wgs84.1), position, 1e-8);
             }
+
+            let bd09 = gcj02_to_bd09(lat, lon);
+            assert_close(bd09_to_gcj02(bd09.0, bd09.1), (lat, lon), 1e-8);
         }
+
+        // No offsets apply outside China.
+        assert_eq!(gcj02_to_wgs84(48.85, 2.35), (48.85, 2.35));
+    }
+
+    #[test]
+    fn test_route_crs() {
+        let line =
+            r#"{"type": "LineString", "coordinates": [[116.404, 39.915], [116.41, 39.915]]}"#;
+        assert_eq!(Route::from_geojson(line, None).unwrap().crs(), Crs::Wgs84);
+
+        let declared = format!(
+            r#"{{"type": "FeatureCollection", "crs": {{"type": "name", "properties": {{"name": "urn:ogc:def:crs:OGC:1.3:CRS84"}}}}, "features": [{{"type": "Feature", "properties": {{"crs": "gcj02"}}, "geometry": {}}}]}}"#,
+            line
+        );
+        assert!(matches!(
+            Route::from_geojson(&declared, None),
+            Err(Error::Route(_))
+        ));
+
+        let gcj02 = format!(
+            r#"{{"type": "FeatureCollection", "crs": "GCJ-02", "features": [{{"type": "Feature", "properties": null, "geometry": {}}}, {{"type": "Feature", "properties": {{"name": "Gate", "checkpoint": true}}, "geometry": {{"type": "Point", "coordinates": [116.404, 39.915]}}}}]}}"#,
+            line
+        );
+        let route = Route::from_geojson(&gcj02, None).unwrap();
+        assert_eq!(route.crs(), Crs::Gcj02);
+        assert_eq!(route.info().crs, Crs::Gcj02);
+        let gate = &route.checkpoints()[0];
+        assert_close(
+            (gate.latitude, gate.longitude),
+            gcj02_to_wgs84(39.915, 116.404),
+            1e-12,
+        );
+
+        // Converted exactly once, to the GCJ-02 sent to the server.
+        let routine = get_routine(0.1, &route, &mut thread_rng()).unwrap();
+        assert!((routine[0].latitude - 39.915).abs() < 1e-5);
+        assert!((routine[0].longitude - 116.404).abs() < 1e-5);
+
+        let route = route.with_crs(Crs::Bd09);
+        let routine = get_routine(0.1, &route, &mut thread_rng()).unwrap();
+        let expected = bd09_to_gcj02(39.915, 116.404);
+        assert!((routine[0].latitude - expected.0).abs() < 1e-5);
+
+        assert!(matches!(
+            Route::from_geojson(
+                r#"{"type": "LineString", "crs": "mercator", "coordinates": [[0, 0], [1, 1]]}"#,
+                None
+            ),
+            Err(Error::Route(_))
+        ));
+        assert_eq!("EPSG:4326".parse::<Crs>().unwrap(), Crs::Wgs84);
+        assert_eq!("bd09ll".parse::<Crs>().unwrap(), Crs::Bd09);
+    }
+
+    #[test]
+    fn test_route_checkpoints() {
+        let collection = r#"{"type": "FeatureCollection", "features": [
+            {"type": "Feature", "properties": {"name": "Gate", "checkpoint": true}, "geometry": {"type": "Point", "coordinates": [114.3005, 30.5001]}},
+            {"type": "Feature", "properties": {"name": "Track"}, "geometry": {"type": "LineString", "coordinates": [[114.3, 30.5], [114.31, 30.5]]}},
+            {"type": "Feature", "properties": {"name": "Library"}, "geometry": {"type": "Point", "coordinates": [114.35, 30.55]}},
+            {"type": "Feature", "properties": {"checkpoint": true}, "geometry": {"type": "MultiPoint", "coordinates": [[114.31, 30.5], [114.32, 30.6]]}}
+        ]}"#;
+
+        let route = Route::from_geojson(collection, None).unwrap();
+        assert_eq!(route.checkpoints().len(), 3);
+        assert_eq!(route.checkpoints()[0].name.as_deref(), Some("Gate"));
+        assert_eq!(route.checkpoints()[2].longitude, 114.32);
+
+        let info = route.info();
+        assert_eq!(info.checkpoints, 3);
+        assert_eq!(info.warnings.len(), 1);
+        assert!(info.warnings[0].starts_with("Checkpoint 2 is"));
+
+        let line: Vec<_> = route
+            .coordinates
+            .iter()
+            .map(|coord| LGPoint::from_wgs84(coord[1], coord[0]))
+            .collect();
+        let segments = split_routine(line.clone(), 2);
+        assert_eq!(segments.len(), 2);
+        assert_eq!(
+            segments[0].last().unwrap().longitude,
+            segments[1][0].longitude
+        );
+        assert_eq!(join_segments(&segments), line);
+        assert!(check_checkpoints(&segments, &route.checkpoints()[..2]).is_ok());
+        assert!(matches!(
+            check_checkpoints(&segments, &route.checkpoints()),
+            Err(Error::Route(_))
+        ));
+    }
+
+    #[test]
+    fn test_route_info() {
+        let route = Route::new(vec![
+            vec![114.3, 30.5],
+            vec![114.3, 30.5],
+            vec![114.31, 30.5],
+            vec![114.31, 30.51],
+            vec![114.3, 30.5001],
+        ])
+        .unwrap();
+
+        let info = route.info();
+        assert_eq!(info.points, 5);
+        assert_eq!(info.duplicate_points, vec![1]);
+        assert_eq!(info.bounding_box.min_longitude, 114.3);
+        assert_eq!(info.bounding_box.max_latitude, 30.51);
+        assert!(info.in_china);
+        assert_eq!(
+            info.max_segment,
+            Point::new(114.31, 30.51).geodesic_distance(&Point::new(114.3, 30.5001))
+        );
+        assert!((info.closure_gap - 11.).abs() < 1.);
+        assert!((info.length - 3.5).abs() < 0.1);
+        assert_eq!(info.warnings.len(), 1);
+
+        let info = Route::new(vec![vec![2.35, 48.85], vec![2.36, 48.85]])
+            .unwrap()
+            .info();
+        assert!(!info.in_china);
+        assert!(info.duplicate_points.is_empty());
+        assert_eq!(info.warnings.len(), 1);
+        // A lap runs there and back.
+        assert!((info.length * 1000. - 2. * info.closure_gap).abs() < 1e-6);
     }
 }
@@ -16,18 +16,19 @@
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use std::error::Error;
-
 use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, BlockEncryptMut, BlockSizeUser, KeyInit};
+use std::borrow::Cow;
+
 use base64::prelude::*;
-use chrono::{Local, NaiveDateTime, TimeZone};
+use chrono::{NaiveDateTime, TimeZone};
+use chrono_tz::Tz;
 use derive_builder::Builder;
 use ecb::{Decryptor, Encryptor};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
 
-use crate::LGPoint;
+use crate::{Error, LGPoint, Result};
 
 #[macro_export]
 macro_rules! uncaesar {
@@ -102,7 +103,7 @@ pub fn hs(text: &str) -> String {
     hex::encode(hasher.finalize())
 }
 
-fn encrypt(plain_data: &str, key: &str) -> Result<String, Box<dyn Error>> {
+fn encrypt(plain_data: &str, key: &str) -> Result<String> {
     let secret_key = get_secret_key(key);
     let cipher = Encryptor::<aes::Aes128>::new(secret_key.as_slice().into());
 
@@ -111,7 +112,7 @@ fn encrypt(plain_data: &str, key: &str) -> Result<String, Box<dyn Error>> {
 
     let ciphertext = cipher
         .encrypt_padded_mut::<Pkcs7>(&mut buffer, plain_data.len())
-        .map_err(|e| e.to_string())?;
+        .map_err(|e| Error::Crypto(e.to_string()))?;
 
     Ok(BASE64_STANDARD.encode(ciphertext))
 }
@@ -128,125 +129,253 @@ struct Oct<'a> {
     ep: u8,
     kt: i64,
     em: f64,
-    rt: &'a str,
-    uer: &'a str,
-    xq: &'a str,
-    dt: &'a str,
+    #[serde(borrow)]
+    rt: Cow<'a, str>,
+    #[serde(borrow)]
+    uer: Cow<'a, str>,
+    #[serde(borrow)]
+    xq: Cow<'a, str>,
+    #[serde(borrow)]
+    dt: Cow<'a, str>,
     bf: f64,
     bs: i64,
     zlc: f64,
     jf: u8,
-    et: &'a str,
-    lid: &'a str,
+    #[serde(borrow)]
+    et: Cow<'a, str>,
+    #[serde(borrow)]
+    lid: Cow<'a, str>,
     kll: i64,
-    app: &'a str,
+    #[serde(borrow)]
+    app: Cow<'a, str>,
     ap: i64,
     lcs: f64,
-    st: &'a str,
-    sv: &'a str,
+    #[serde(borrow)]
+    st: Cow<'a, str>,
+    #[serde(borrow)]
+    sv: Cow<'a, str>,
 }
 
-pub fn sign_run_data(
-    data: &mut UploadRunningInfo,
-    a1: &str,
-    a2: &str,
-) -> Result<(), Box<dyn Error>> {
-    let oct = Oct {
-        tp: data.total_part,
-        ep: data.effective_part,
-        kt: data.keep_time,
-        em: data.effective_mileage,
-        rt: &data.run_type,
-        uer: &data.uneffective_reason,
-        xq: &data.semester_id,
-        dt: &data.device_type,
-        bf: data.pace_range,
-        bs: data.pace_number,
-        zlc: data.total_mileage,
-        jf: data.scoring_type,
-        et: &data.end_time,
-        lid: &data.limitations_goals_sex_info_id,
-        kll: data.calorie,
-        app: &data.app_version,
-        ap: data.ave_pace,
-        lcs: data.gps_mileage,
-        st: &data.start_time,
-        sv: &data.system_version,
-    };
-
-    let json_data = serde_json::to_string_pretty(&oct)?;
-
-    let re = Regex::new(": ")?;
-    let formatted_json = re.replace_all(&json_data, " : ").to_string();
+/// The payload field summarized by each field of [`Oct`].
+const OCT_FIELDS: [(&str, &str); 20] = [
+    ("tp", "totalPart"),
+    ("ep", "effectivePart"),
+    ("kt", "keepTime"),
+    ("em", "effectiveMileage"),
+    ("rt", "type"),
+    ("uer", "uneffectiveReason"),
+    ("xq", "semesterId"),
+    ("dt", "deviceType"),
+    ("bf", "paceRange"),
+    ("bs", "paceNumber"),
+    ("zlc", "totalMileage"),
+    ("jf", "scoringType"),
+    ("et", "endTime"),
+    ("lid", "limitationsGoalsSexInfoId"),
+    ("kll", "calorie"),
+    ("app", "appVersion"),
+    ("ap", "avePace"),
+    ("lcs", "gpsMileage"),
+    ("st", "startTime"),
+    ("sv", "systemVersion"),
+];
+
+impl<'a> From<&'a UploadRunningInfo> for Oct<'a> {
+    fn from(data: &'a UploadRunningInfo) -> Self {
+        Self {
+            tp: data.total_part,
+            ep: data.effective_part,
+            kt: data.keep_time,
+            em: data.effective_mileage,
+            rt: data.run_type.as_str().into(),
+            uer: data.uneffective_reason.as_str().into(),
+            xq: data.semester_id.as_str().into(),
+            dt: data.device_type.as_str().into(),
+            bf: data.pace_range,
+            bs: data.pace_number,
+            zlc: data.total_mileage,
+            jf: data.scoring_type,
+            et: data.end_time.as_str().into(),
+            lid: data.limitations_goals_sex_info_id.as_str().into(),
+            kll: data.calorie,
+            app: data.app_version.as_str().into(),
+            ap: data.ave_pace,
+            lcs: data.gps_mileage,
+            st: data.start_time.as_str().into(),
+            sv: data.system_version.as_str().into(),
+        }
+    }
+}
 
-    let dy_key = get_rn_key(a1, a2);
+fn sign_digital(data: &UploadRunningInfo) -> String {
+    hs(&format!(
+        "{}{}{}{}{}{}{}{}{}",
+        data.effective_mileage,
+        data.effective_part,
+        data.start_time,
+        data.calorie,
+        data.ave_pace,
+        data.keep_time,
+        data.pace_number,
+        data.total_mileage,
+        data.total_part,
+    ))
+}
 
+fn sign_time(data: &UploadRunningInfo, timezone: Tz) -> Result<String> {
     let end_time = NaiveDateTime::parse_from_str(&data.end_time, "%Y-%m-%d %H:%M:%S")?;
-    let end_time = Local
+    let end_time = timezone
         .from_local_datetime(&end_time)
         .single()
-        .ok_or("Error getting end_time")?;
+        .ok_or_else(|| Error::Decode("Error getting end_time".to_string()))?;
 
     let sign_time = end_time.timestamp() + data.keep_time % 11;
-    let sign_time = Local
+    let sign_time = timezone
         .timestamp_opt(sign_time, 0)
         .single()
-        .ok_or("Error getting sign_time")?;
+        .ok_or_else(|| Error::Decode("Error getting sign_time".to_string()))?;
 
-    data.sign_time = sign_time.format("%Y-%m-%d %H:%M:%S").to_string();
+    Ok(sign_time.format("%Y-%m-%d %H:%M:%S").to_string())
+}
+
+/// Fills in `sign_digital`, `sign_time` and the encrypted `oct` summary,
+/// reading the times of the run in the server's time zone.
+pub fn sign_run_data(data: &mut UploadRunningInfo, a1: &str, a2: &str, timezone: Tz) -> Result<()> {
+    let json_data = serde_json::to_string_pretty(&Oct::from(&*data))?;
+
+    let re = Regex::new(": ")?;
+    let formatted_json = re.replace_all(&json_data, " : ").to_string();
+
+    let dy_key = get_rn_key(a1, a2)?;
+
+    data.sign_digital = sign_digital(data);
+    data.sign_time = sign_time(data, timezone)?;
     data.oct = encrypt(&formatted_json, &dy_key)?;
 
     Ok(())
 }
 
+/// A payload field that disagrees with the signature computed from it.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Mismatch {
+    /// Name of the field in the payload.
+    pub field: String,
+    /// Value according to the signature.
+    pub signed: String,
+    /// Value found in the payload.
+    pub found: String,
+}
+
+/// Checks a signed upload payload against its own signatures.
+///
+/// Decrypts `oct` with the key of the given user and school, and recomputes
+/// `signDigital` and `signTime`, reading the times in the server's time zone.
+/// Returns every field that disagrees, so an empty list means the payload is
+/// consistent.
+pub fn verify_payload(
+    payload: &str,
+    user_id: &str,
+    school_id: &str,
+    timezone: Tz,
+) -> Result<Vec<Mismatch>> {
+    let data: UploadRunningInfo = serde_json::from_str(payload)?;
+    let plain = decrypt(&data.oct, &get_rn_key(user_id, school_id)?)?;
+    let oct: Oct =
+        serde_json::from_str(&plain).map_err(|e| Error::Crypto(format!("Invalid oct: {}", e)))?;
+
+    let signed = serde_json::to_value(&oct)?;
+    let found = serde_json::to_value(Oct::from(&data))?;
+
+    let mut mismatches: Vec<_> = OCT_FIELDS
+        .into_iter()
+        .filter(|(short, _)| signed[short] != found[short])
+        .map(|(short, field)| Mismatch {
+            field: field.to_string(),
+            signed: signed[short].to_string(),
+            found: found[short].to_string(),
+        })
+        .collect();
+
+    for (field, signed, found) in [
+        ("signDigital", sign_digital(&data), &data.sign_digital),
+        ("signTime", sign_time(&data, timezone)?, &data.sign_time),
+    ] {
+        if signed != *found {
+            mismatches.push(Mismatch {
+                field: field.to_string(),
+                signed,
+                found: found.clone(),
+            });
+        }
+    }
+
+    Ok(mismatches)
+}
+
 const RN_FIXED: &str = uncaesar!("3h0783g6891d4d3h9521gfe6ee341560");
 
-fn get_rn_key(a1: &str, a2: &str) -> String {
-    let dest = &a1[3..6];
-    let v14 = &a2[4..7];
-    let v13 = &a1[9..12];
+pub(crate) fn get_rn_key(a1: &str, a2: &str) -> Result<String> {
+    let slice = |s: &'static str, text: &str, range: std::ops::Range<usize>| {
+        text.get(range)
+            .map(str::to_string)
+            .ok_or_else(|| Error::Crypto(format!("Invalid {}: {}", s, text)))
+    };
+
+    let dest = slice("user id", a1, 3..6)?;
+    let v14 = slice("school id", a2, 4..7)?;
+    let v13 = slice("user id", a1, 9..12)?;
 
-    format!("{}{}{}{}", dest, v14, v13, RN_FIXED)
+    Ok(format!("{}{}{}{}", dest, v14, v13, RN_FIXED))
 }
 
 const DYNAMIC_FIXED: &str = uncaesar!("402881hd7f39f5g5017f39g143d8062e");
 
-fn get_dynamic_key(a1: &str) -> Result<String, Box<dyn Error>> {
+fn get_dynamic_key(a1: &str) -> Result<String> {
     let dest = &a1[2..5];
     let nptr = &a1[4..8];
-    let v2 = a1.chars().last().ok_or("Invalid string")?;
-
-    let v1 = dest.parse::<i32>()?;
-    let v3 = v1 - nptr.parse::<i32>()?;
+    let v2 = a1
+        .chars()
+        .last()
+        .ok_or_else(|| Error::Crypto("Invalid string".to_string()))?;
+
+    let parse = |s: &str| s.parse::<i32>().map_err(|e| Error::Crypto(e.to_string()));
+    let v1 = parse(dest)?;
+    let v3 = v1 - parse(nptr)?;
     let v4 = v3.abs();
-    let v5 = v4 << v2.to_digit(10).ok_or("Invalid digit")?;
+    let v5 = v4
+        << v2
+            .to_digit(10)
+            .ok_or_else(|| Error::Crypto("Invalid digit".to_string()))?;
 
     let fixed_string = DYNAMIC_FIXED;
 
     Ok(format!("{}{}", v5, fixed_string))
 }
 
-pub fn encode_ns(text: &str, t: i64) -> Result<String, Box<dyn Error>> {
+pub fn encode_ns(text: &str, t: i64) -> Result<String> {
     let key = get_dynamic_key(&t.to_string())?;
     encrypt(text, &key)
 }
 
-fn decrypt(text: &str, key: &str) -> Result<String, Box<dyn Error>> {
+pub(crate) fn decrypt(text: &str, key: &str) -> Result<String> {
     let secret_key = get_secret_key(key);
     let cipher = Decryptor::<aes::Aes128>::new(secret_key.as_slice().into());
 
-    let ciphertext = BASE64_STANDARD.decode(text.as_bytes())?;
+    let ciphertext = BASE64_STANDARD
+        .decode(text.as_bytes())
+        .map_err(|e| Error::Crypto(e.to_string()))?;
     let mut buffer = vec![0u8; ciphertext.len()];
 
     let plaintext_len = cipher
         .decrypt_padded_b2b_mut::<Pkcs7>(&ciphertext, &mut buffer)
-        .map_err(|e| e.to_string())?
+        .map_err(|e| Error::Crypto(e.to_string()))?
         .len();
 
     Ok(String::from_utf8_lossy(&buffer[..plaintext_len]).to_string())
 }
 
-pub fn decode_ns(text: &str, t: i64) -> Result<String, Box<dyn Error>> {
+pub fn decode_ns(text: &str, t: i64) -> Result<String> {
     let key = get_dynamic_key(&t.to_string())?;
     decrypt(text, &key)
 }
@@ -254,6 +383,7 @@ pub fn decode_ns(text: &str, t: i64) -> Result<String, Box<dyn Error>> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::DEFAULT_TIMEZONE;
 
     #[test]
     fn test_hs1() {
@@ -300,4 +430,124 @@ mod tests {
 
         assert_eq!(decoded, expected);
     }
+
+    fn signed_payload() -> serde_json::Value {
+        let mut data = UploadRunningInfoBuilder::default()
+            .effective_mileage(2.5)
+            .total_mileage(2.5)
+            .effective_part(1)
+            .total_part(1)
+            .keep_time(900)
+            .start_time("2024-09-20 20:00:00".to_string())
+            .end_time("2024-09-20 20:15:08".to_string())
+            .run_type("自由跑".to_string())
+            .semester_id("semester".to_string())
+            .build()
+            .unwrap();
+        sign_run_data(
+            &mut data,
+            "0123456789abcdef",
+            "fedcba9876543210",
+            DEFAULT_TIMEZONE,
+        )
+        .unwrap();
+        serde_json::to_value(data).unwrap()
+    }
+
+    #[test]
+    fn test_verify_payload() {
+        let payload = signed_payload();
+        let mismatches = verify_payload(
+            &payload.to_string(),
+            "0123456789abcdef",
+            "fedcba9876543210",
+            DEFAULT_TIMEZONE,
+        )
+        .unwrap();
+        assert!(mismatches.is_empty(), "{:?}", mismatches);
+
+        let mut tampered = payload.clone();
+        tampered["effectiveMileage"] = 3.0.into();
+        tampered["type"] = "晨跑".into();
+        let mismatches = verify_payload(
+            &tampered.to_string(),
+            "0123456789abcdef",
+            "fedcba9876543210",
+            DEFAULT_TIMEZONE,
+        )
+        .unwrap();
+        let fields: Vec<_> = mismatches.iter().map(|m| m.field.as_str()).collect();
+        assert_eq!(fields, ["effectiveMileage", "type", "signDigital"]);
+        assert_eq!(mismatches[0].signed, "2.5");
+        assert_eq!(mismatches[0].found, "3.0");
+    }
+
+    #[test]
+    fn test_sign_time_timezone() {
+        // The end time is read, and the sign time written, in the given zone.
+        let payload = signed_payload();
+        assert_eq!(payload["signTime"], "2024-09-20 20:15:17");
+
+        // The sign time crosses the start of summer time in London, but not
+        // in Shanghai.
+        let mut data: UploadRunningInfo = serde_json::from_value(payload).unwrap();
+        data.end_time = "2024-03-31 00:59:55".to_string();
+        assert_eq!(
+            sign_time(&data, DEFAULT_TIMEZONE).unwrap(),
+            "2024-03-31 01:00:04"
+        );
+        assert_eq!(
+            sign_time(&data, chrono_tz::Europe::London).unwrap(),
+            "2024-03-31 02:00:04"
+        );
+
+        // An end time skipped by the clock change only exists in Shanghai.
+        data.end_time = "2024-03-31 01:30:00".to_string();
+        assert!(sign_time(&data, DEFAULT_TIMEZONE).is_ok());
+        assert!(matches!(
+            sign_time(&data, chrono_tz::Europe::London),
+            Err(Error::Decode(_))
+        ));
+    }
+
+    #[test]
+    fn test_sign_time_host_timezone() {
+        // Reruns itself in a child process with another host time zone, as
+        // setting `TZ` here would leak into the other tests.
+        const CHILD: &str = "PRETTY_DER6Y_SIGN_TIME_CHILD";
+        if std::env::var_os(CHILD).is_some() {
+            assert_eq!(signed_payload()["signTime"], "2024-09-20 20:15:17");
+            return;
+        }
+
+        let module = module_path!().split_once("::").unwrap().1;
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .args([
+                "--exact",
+                &format!("{}::test_sign_time_host_timezone", module),
+            ])
+            .env(CHILD, "1")
+            .env("TZ", "America/New_York")
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_verify_payload_wrong_key() {
+        let payload = signed_payload().to_string();
+        assert!(matches!(
+            verify_payload(
+                &payload,
+                "0000000000000000",
+                "fedcba9876543210",
+                DEFAULT_TIMEZONE
+            ),
+            Err(Error::Crypto(_))
+        ));
+        assert!(matches!(
+            verify_payload(&payload, "short", "fedcba9876543210", DEFAULT_TIMEZONE),
+            Err(Error::Crypto(_))
+        ));
+    }
 }
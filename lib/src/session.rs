@@ -0,0 +1,79 @@
+/*
+    Pretty Der6y - A third-party running data upload client.
+    Copyright (C) 2024  Fay Ash
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published
+    by the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Result, RunningLimits, Semester};
+
+/// A snapshot of a logged-in [`Account`](crate::Account).
+///
+/// It can be stored with serde and restored later, so that the account does
+/// not have to log in again.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Session {
+    pub(crate) token: String,
+    pub(crate) user_id: String,
+    pub(crate) school_id: String,
+    pub(crate) organization_id: String,
+    pub(crate) semester: Semester,
+    pub(crate) version: String,
+    pub(crate) limits: RunningLimits,
+}
+
+impl Session {
+    pub fn user_id(&self) -> &str {
+        &self.user_id
+    }
+
+    pub fn school_id(&self) -> &str {
+        &self.school_id
+    }
+
+    /// Reads a session saved by [`Session::save`].
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// Writes the session to `path`, creating its parent directories.
+    ///
+    /// The session holds the access token, so on Unix the file is only
+    /// readable by its owner, even if it was saved before with wider
+    /// permissions.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(path)?;
+        #[cfg(unix)]
+        file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+        file.write_all(serde_json::to_string(self)?.as_bytes())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,179 @@
+/*
+    Pretty Der6y - A third-party running data upload client.
+    Copyright (C) 2024  Fay Ash
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License as published
+    by the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use chrono::{DateTime, Utc};
+use geo::{prelude::*, Point};
+
+use crate::{fit, gpx, Error, Result};
+
+/// A recorded position, in WGS-84.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrackPoint {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub time: DateTime<Utc>,
+}
+
+impl TrackPoint {
+    fn point(&self) -> Point {
+        Point::new(self.longitude, self.latitude)
+    }
+}
+
+/// A recorded activity, split into segments wherever the recording paused.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Track {
+    segments: Vec<Vec<TrackPoint>>,
+    recorded_distance: Option<f64>,
+}
+
+impl Track {
+    /// Creates a track from its segments, dropping empty ones.
+    ///
+    /// Fails if there are no points or if the timestamps go backwards.
+    pub fn new(segments: Vec<Vec<TrackPoint>>) -> Result<Self> {
+        let segments: Vec<_> = segments
+            .into_iter()
+            .filter(|segment| !segment.is_empty())
+            .collect();
+
+        if segments.is_empty() {
+            return Err(Error::Route("No track points found".to_string()));
+        }
+
+        let mut points = segments.iter().flatten();
+        let mut last = points.next().map(|point| point.time);
+        for point in points {
+            if Some(point.time) < last {
+                return Err(Error::Route(format!(
+                    "Track point at {} is out of order",
+                    point.time
+                )));
+            }
+            last = Some(point.time);
+        }
+
+        Ok(Self {
+            segments,
+            recorded_distance: None,
+        })
+    }
+
+    /// Parses the tracks of a GPX 1.1 document, one segment per `<trkseg>`.
+    pub fn from_gpx(xml: &str) -> Result<Self> {
+        Self::new(gpx::parse(xml)?)
+    }
+
+    /// Decodes the record messages of a FIT activity file, starting a new
+    /// segment wherever the timer was stopped.
+    ///
+    /// The distance measured by the device is kept and preferred over the one
+    /// computed from the positions.
+    pub fn from_fit(data: &[u8]) -> Result<Self> {
+        let activity = fit::parse(data)?;
+        Ok(Self {
+            recorded_distance: activity.distance,
+            ..Self::new(activity.segments)?
+        })
+    }
+
+    pub fn segments(&self) -> &[Vec<TrackPoint>] {
+        &self.segments
+    }
+
+    pub fn points(&self) -> impl Iterator<Item = &TrackPoint> {
+        self.segments.iter().flatten()
+    }
+
+    /// Length of the track in kilometers, pauses excluded.
+    ///
+    /// This is the distance recorded by the device if the file carries one,
+    /// and the geodesic length of all segments otherwise.
+    pub fn distance(&self) -> f64 {
+        if let Some(distance) = self.recorded_distance {
+            return distance;
+        }
+
+        self.segments
+            .iter()
+            .flat_map(|segment| segment.windows(2))
+            .map(|pair| pair[0].point().geodesic_distance(&pair[1].point()))
+            .sum::<f64>()
+            / 1000.
+    }
+
+    /// Recorded time of all segments in seconds, pauses excluded.
+    pub fn keep_time(&self) -> i64 {
+        self.segments
+            .iter()
+            .map(|segment| (segment[segment.len() - 1].time - segment[0].time).num_seconds())
+            .sum()
+    }
+
+    pub fn start_time(&self) -> DateTime<Utc> {
+        self.segments[0][0].time
+    }
+
+    pub fn end_time(&self) -> DateTime<Utc> {
+        let last = &self.segments[self.segments.len() - 1];
+        last[last.len() - 1].time
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_fit() {
+        let data = fit::tests::encode(
+            &[
+                (30.5, 114.3, 1_000_000_000, 0),
+                (30.51, 114.3, 1_000_000_360, 105_000),
+                (30.52, 114.3, 1_000_000_900, 210_000),
+            ],
+            &[1],
+        );
+
+        let track = Track::from_fit(&data).unwrap();
+        assert_eq!(track.segments().len(), 2);
+        assert_eq!(track.points().count(), 3);
+        assert_eq!(track.distance(), 2.1);
+        assert_eq!(track.keep_time(), 360);
+        assert_eq!((track.end_time() - track.start_time()).num_seconds(), 900);
+    }
+
+    #[test]
+    fn test_new_out_of_order() {
+        let point = |seconds| TrackPoint {
+            latitude: 30.5,
+            longitude: 114.3,
+            time: DateTime::from_timestamp(seconds, 0).unwrap(),
+        };
+
+        assert!(Track::new(vec![vec![], vec![]]).is_err());
+        assert!(Track::new(vec![vec![point(10), point(5)]]).is_err());
+        assert_eq!(
+            Track::new(vec![vec![], vec![point(5), point(10)]])
+                .unwrap()
+                .segments()
+                .len(),
+            1
+        );
+    }
+}
@@ -16,37 +16,352 @@
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
+use std::{collections::HashMap, error::Error, fs, path::PathBuf};
+
 use lib::{
-    chrono::{DateTime, Local},
-    Account,
+    chrono::{DateTime, Utc},
+    chrono_tz::Tz,
+    Account, Endpoint, MileageMode, Route, Session, UploadRequest,
 };
+use serde::{Deserialize, Serialize};
+use specta::Type;
 
 #[cfg(debug_assertions)]
 use specta_typescript::{formatter, BigIntExportBehavior, Typescript};
-use tauri::{async_runtime::Mutex, Manager, State};
+use tauri::{async_runtime::Mutex, AppHandle, Manager, State};
 use tauri_specta::{collect_commands, Builder};
 
+/// Settings read from `config.json` in the app config directory.
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Config {
+    base_url: Option<String>,
+    #[serde(default)]
+    endpoints: HashMap<Endpoint, String>,
+    #[serde(default)]
+    mileage_mode: MileageMode,
+    timezone: Option<Tz>,
+}
+
+impl Config {
+    fn load(app: &AppHandle) -> Result<Self, Box<dyn Error>> {
+        let path = app.path().app_config_dir()?.join("config.json");
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    fn account(self) -> lib::Result<Account> {
+        let mut builder = Account::builder().mileage_mode(self.mileage_mode);
+        if let Some(base_url) = self.base_url {
+            builder = builder.base_url(base_url);
+        }
+        if let Some(timezone) = self.timezone {
+            builder = builder.timezone(timezone);
+        }
+        for (endpoint, url) in self.endpoints {
+            builder = builder.endpoint(endpoint, url);
+        }
+        builder.build()
+    }
+}
+
+/// Error forwarded to the frontend, carrying the kind of failure alongside its message.
+#[derive(Debug, Serialize, Type)]
+struct CommandError {
+    code: String,
+    message: String,
+}
+
+impl CommandError {
+    fn new(code: &str, message: &str) -> Self {
+        Self {
+            code: code.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<lib::Error> for CommandError {
+    fn from(e: lib::Error) -> Self {
+        Self::new(e.code(), &e.to_string())
+    }
+}
+
+/// Extent of a route, in WGS-84.
+#[derive(Serialize, Type)]
+#[serde(rename_all = "camelCase")]
+struct BoundingBox {
+    min_longitude: f64,
+    min_latitude: f64,
+    max_longitude: f64,
+    max_latitude: f64,
+}
+
+/// Statistics of a route, lengths in kilometers and distances in meters.
+#[derive(Serialize, Type)]
+#[serde(rename_all = "camelCase")]
+struct RouteInfo {
+    length: f64,
+    points: usize,
+    bounding_box: BoundingBox,
+    max_segment: f64,
+    closure_gap: f64,
+    in_china: bool,
+    duplicate_points: Vec<usize>,
+    checkpoints: usize,
+    crs: String,
+    warnings: Vec<String>,
+}
+
+impl From<lib::RouteInfo> for RouteInfo {
+    fn from(info: lib::RouteInfo) -> Self {
+        let bbox = info.bounding_box;
+        Self {
+            length: info.length,
+            points: info.points,
+            bounding_box: BoundingBox {
+                min_longitude: bbox.min_longitude,
+                min_latitude: bbox.min_latitude,
+                max_longitude: bbox.max_longitude,
+                max_latitude: bbox.max_latitude,
+            },
+            max_segment: info.max_segment,
+            closure_gap: info.closure_gap,
+            in_china: info.in_china,
+            duplicate_points: info.duplicate_points,
+            checkpoints: info.checkpoints,
+            crs: info.crs.to_string(),
+            warnings: info.warnings,
+        }
+    }
+}
+
+/// Running limits of the current semester, in kilometers.
+#[derive(Serialize, Type)]
+#[serde(rename_all = "camelCase")]
+struct RunningLimits {
+    daily: f64,
+    weekly: f64,
+    today: f64,
+    this_week: f64,
+    effective_start: f64,
+    effective_end: f64,
+    remaining_daily: f64,
+    remaining_weekly: f64,
+}
+
+impl From<&lib::RunningLimits> for RunningLimits {
+    fn from(limits: &lib::RunningLimits) -> Self {
+        Self {
+            daily: limits.daily(),
+            weekly: limits.weekly(),
+            today: limits.today(),
+            this_week: limits.this_week(),
+            effective_start: limits.effective_start(),
+            effective_end: limits.effective_end(),
+            remaining_daily: limits.remaining_daily(),
+            remaining_weekly: limits.remaining_weekly(),
+        }
+    }
+}
+
+/// How a requested mileage fits the running limits, in kilometers.
+#[derive(Serialize, Type)]
+#[serde(rename_all = "camelCase")]
+struct MileageCheck {
+    requested: f64,
+    adjusted: f64,
+    clamped: bool,
+    within_daily: bool,
+    within_weekly: bool,
+    within_range: bool,
+}
+
+impl From<lib::MileageCheck> for MileageCheck {
+    fn from(check: lib::MileageCheck) -> Self {
+        Self {
+            requested: check.requested,
+            adjusted: check.adjusted,
+            clamped: check.is_clamped(),
+            within_daily: check.within_daily,
+            within_weekly: check.within_weekly,
+            within_range: check.within_range,
+        }
+    }
+}
+
+/// What the server recorded for an upload.
+#[derive(Serialize, Type)]
+#[serde(rename_all = "camelCase")]
+struct UploadReceipt {
+    record_id: Option<String>,
+    effective_mileage: Option<f64>,
+    effective: bool,
+    uneffective_reason: Option<String>,
+    mileage: MileageCheck,
+}
+
+impl From<lib::UploadReceipt> for UploadReceipt {
+    fn from(receipt: lib::UploadReceipt) -> Self {
+        Self {
+            effective: receipt.is_effective(),
+            record_id: receipt.record_id,
+            effective_mileage: receipt.effective_mileage,
+            uneffective_reason: receipt.uneffective_reason,
+            mileage: receipt.mileage.into(),
+        }
+    }
+}
+
+#[derive(Serialize, Type)]
+struct Semester {
+    id: String,
+    name: Option<String>,
+}
+
+impl From<&lib::Semester> for Semester {
+    fn from(semester: &lib::Semester) -> Self {
+        Self {
+            id: semester.id().to_string(),
+            name: semester.name().map(str::to_string),
+        }
+    }
+}
+
+fn session_path(app: &AppHandle) -> tauri::Result<PathBuf> {
+    Ok(app.path().app_config_dir()?.join("session.json"))
+}
+
+fn save_session(app: &AppHandle, session: &Session) -> Result<(), Box<dyn Error>> {
+    session.save(session_path(app)?)?;
+    Ok(())
+}
+
 #[tauri::command]
 #[specta::specta]
 async fn login(
+    app: AppHandle,
     state: State<'_, Mutex<Account>>,
     username: &str,
     password: &str,
-) -> Result<(), String> {
+) -> Result<(), CommandError> {
     let mut account = state.lock().await;
-    account
-        .login(username, password)
-        .await
-        .map_err(|e| e.to_string())
+    account.login(username, password).await?;
+
+    // Caching the session is best-effort, the login itself succeeded.
+    if let Some(session) = account.export_session() {
+        let _ = save_session(&app, &session);
+    }
+    Ok(())
 }
 
+/// Restores the session saved by the last login, returns whether it is still valid.
 #[tauri::command]
 #[specta::specta]
-async fn get_daily_limit(state: State<'_, Mutex<Account>>) -> Result<f64, String> {
+async fn restore_session(
+    app: AppHandle,
+    state: State<'_, Mutex<Account>>,
+) -> Result<bool, CommandError> {
+    let Ok(path) = session_path(&app) else {
+        return Ok(false);
+    };
+    let Ok(session) = Session::load(&path) else {
+        return Ok(false);
+    };
+
+    let mut account = state.lock().await;
+    let restored = account.restore_session(session).await?;
+    if !restored {
+        let _ = fs::remove_file(path);
+    }
+    Ok(restored)
+}
+
+#[tauri::command]
+#[specta::specta]
+async fn get_daily_limit(state: State<'_, Mutex<Account>>) -> Result<f64, CommandError> {
     let account = state.lock().await;
     Ok(account.daily())
 }
 
+#[tauri::command]
+#[specta::specta]
+async fn get_running_limits(
+    state: State<'_, Mutex<Account>>,
+) -> Result<RunningLimits, CommandError> {
+    let account = state.lock().await;
+    Ok(account.running_limits().into())
+}
+
+#[tauri::command]
+#[specta::specta]
+async fn get_semester(state: State<'_, Mutex<Account>>) -> Result<Semester, CommandError> {
+    let account = state.lock().await;
+    Ok(account.semester().into())
+}
+
+/// Fetches the running limits again, e.g. after an upload.
+#[tauri::command]
+#[specta::specta]
+async fn refresh_limits(state: State<'_, Mutex<Account>>) -> Result<RunningLimits, CommandError> {
+    let mut account = state.lock().await;
+    account.refresh_limits().await?;
+    Ok(account.running_limits().into())
+}
+
+/// Checks a mileage against the running limits before it is uploaded.
+#[tauri::command]
+#[specta::specta]
+async fn check_mileage(
+    state: State<'_, Mutex<Account>>,
+    mileage: f64,
+) -> Result<MileageCheck, CommandError> {
+    let account = state.lock().await;
+    Ok(account.check_mileage(mileage).into())
+}
+
+/// Checks a GeoJSON route before it is uploaded.
+#[tauri::command]
+#[specta::specta]
+fn route_info(geojson: &str) -> Result<RouteInfo, CommandError> {
+    Ok(Route::from_geojson(geojson, None)?.info().into())
+}
+
+fn parse_end_time(millis: i64) -> Result<DateTime<Utc>, CommandError> {
+    DateTime::from_timestamp_millis(millis)
+        .ok_or_else(|| CommandError::new("argument", "Invalid timestamp"))
+}
+
+fn upload_request(
+    geojson: &str,
+    mileage: f64,
+    end_time: i64,
+) -> Result<UploadRequest, CommandError> {
+    Ok(UploadRequest::builder()
+        .route(Route::from_geojson(geojson, None)?)
+        .distance(mileage)
+        .end_time(parse_end_time(end_time)?)
+        .build()?)
+}
+
+/// Builds the signed payload an upload would send, as pretty-printed JSON.
+#[tauri::command]
+#[specta::specta]
+async fn preview_payload(
+    state: State<'_, Mutex<Account>>,
+    geojson: &str,
+    mileage: f64,
+    end_time: i64,
+) -> Result<String, CommandError> {
+    let account = state.lock().await;
+    let payload = account.preview(&upload_request(geojson, mileage, end_time)?)?;
+
+    Ok(format!("{:#}", payload))
+}
+
 #[tauri::command]
 #[specta::specta]
 async fn upload(
@@ -54,22 +369,27 @@ async fn upload(
     geojson: &str,
     mileage: f64,
     end_time: i64,
-) -> Result<(), String> {
+) -> Result<UploadReceipt, CommandError> {
+    let request = upload_request(geojson, mileage, end_time)?;
     let mut account = state.lock().await;
-    let end_time: DateTime<Local> = DateTime::from_timestamp_millis(end_time)
-        .ok_or("Invalid timestamp")?
-        .with_timezone(&Local);
 
-    account
-        .upload_running(geojson, mileage, &end_time)
-        .await
-        .map_err(|e| e.to_string())
+    Ok(account.upload(&request).await?.into())
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
-    let _builder =
-        Builder::<tauri::Wry>::new().commands(collect_commands![login, get_daily_limit, upload,]);
+    let _builder = Builder::<tauri::Wry>::new().commands(collect_commands![
+        login,
+        restore_session,
+        get_daily_limit,
+        get_running_limits,
+        get_semester,
+        refresh_limits,
+        check_mileage,
+        route_info,
+        preview_payload,
+        upload,
+    ]);
 
     #[cfg(debug_assertions)] // only export typescript bindings in debug mode
     #[cfg(desktop)]
@@ -88,12 +408,23 @@ pub fn run() {
             app.handle()
                 .plugin(tauri_plugin_updater::Builder::new().build())?;
 
-            let account = Account::new();
+            let account = Config::load(app.handle())?.account()?;
             app.manage(Mutex::new(account));
 
             Ok(())
         })
-        .invoke_handler(tauri::generate_handler![login, get_daily_limit, upload])
+        .invoke_handler(tauri::generate_handler![
+            login,
+            restore_session,
+            get_daily_limit,
+            get_running_limits,
+            get_semester,
+            refresh_limits,
+            check_mileage,
+            route_info,
+            preview_payload,
+            upload
+        ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
@@ -28,6 +28,7 @@ import Login from "./Login.tsx";
 import { LoggerProvider, useLogger } from "@components/Logger.tsx";
 import TittleBar from "@components/TittleBar.tsx";
 import { check, type Update } from "@tauri-apps/plugin-updater";
+import { commands } from "@helpers/bindings";
 
 const Main = lazy(() => import("./Main.tsx"));
 
@@ -38,10 +39,24 @@ export function useLogined() {
 }
 
 function Body() {
-  const [logined] = useLogined();
+  const [logined, setLogined] = useLogined();
   const logger = useLogger();
   const [update, setUpdate] = createSignal<Update | null>(null);
 
+  onMount(() => {
+    commands
+      .restoreSession()
+      .then((res) =>
+        res.status === "ok"
+          ? setLogined(res.data)
+          : logger?.warn(`Error restoring session: ${res.error.message}`),
+      )
+      .catch((error) => {
+        const message = error instanceof Error ? error.message : error;
+        logger?.warn(`Error restoring session: ${message}`);
+      });
+  });
+
   onMount(async () => {
     if (window.innerWidth > 768) {
       try {
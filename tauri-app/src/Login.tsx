@@ -58,7 +58,7 @@ export default function Login() {
                 .then((res) =>
                   res.status === "ok"
                     ? setLogined(true)
-                    : logger?.error(`Error logging in: ${res.error}`),
+                    : logger?.error(`Error logging in: ${res.error.message}`),
                 )
                 .catch((error) => {
                   logger?.error(`Error logging in: ${error}`);
@@ -16,7 +16,14 @@
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-import { createMemo, createSignal, onCleanup, onMount } from "solid-js";
+import {
+  createEffect,
+  createMemo,
+  createSignal,
+  onCleanup,
+  onMount,
+  Show,
+} from "solid-js";
 import DatePicker from "@components/DatePicker";
 import LeafletMap from "@components/LeafletMap";
 import TwoColumn from "@layouts/TwoColumn";
@@ -26,20 +33,37 @@ import Uploader from "@components/Uploader";
 import Button from "@components/Button";
 import { useLogger } from "@components/Logger";
 import * as L from "leaflet";
-import { commands } from "@helpers/bindings";
+import {
+  commands,
+  type MileageCheck,
+  type RunningLimits,
+} from "@helpers/bindings";
 import isDef from "@helpers/isDef";
+import { useLogined } from "./App";
 
 export default function Main() {
   const logger = useLogger();
+  const [, setLogined] = useLogined();
   const [time, setTime] = createSignal(new Date());
   const [percentage, setPercentage] = createSignal(0);
   const [file, setFile] = createSignal<File>();
   const [map, setMap] = createSignal<L.Map>();
   const [daily, setDaily] = createSignal(0);
   const [pending, setPending] = createSignal(false);
+  const [limits, setLimits] = createSignal<RunningLimits>();
+  const [check, setCheck] = createSignal<MileageCheck>();
 
   const mileage = createMemo(() => (percentage() * daily()) / 100);
 
+  createEffect(() => {
+    // Refresh the check whenever the mileage or the limits change.
+    limits();
+    commands
+      .checkMileage(mileage())
+      .then((res) => res.status === "ok" && setCheck(res.data))
+      .catch(() => setCheck());
+  });
+
   let tick: number;
 
   onMount(() => {
@@ -57,14 +81,34 @@ export default function Main() {
       .then((res) =>
         res.status === "ok"
           ? setDaily(res.data)
-          : logger?.error(`Error getting daily limit: ${res.error}`),
+          : logger?.error(`Error getting daily limit: ${res.error.message}`),
       )
       .catch((error) => {
         const message = error instanceof Error ? error.message : error;
         logger?.error(`Error getting daily limit: ${message}`);
       });
+
+    commands
+      .getRunningLimits()
+      .then((res) => res.status === "ok" && setLimits(res.data))
+      .catch((error) => {
+        logger?.error(`Error getting running limits: ${error}`);
+      });
   });
 
+  function refreshLimits() {
+    commands
+      .refreshLimits()
+      .then((res) =>
+        res.status === "ok"
+          ? setLimits(res.data)
+          : logger?.error(`Error refreshing limits: ${res.error.message}`),
+      )
+      .catch((error) => {
+        logger?.error(`Error refreshing limits: ${error}`);
+      });
+  }
+
   onCleanup(() => {
     clearTimeout(tick);
   });
@@ -86,6 +130,24 @@ export default function Main() {
       reader.onload = (event) => {
         const data = event.target?.result;
         if (typeof data === "string") {
+          commands
+            .routeInfo(data)
+            .then((res) => {
+              if (res.status === "ok") {
+                logger?.info(
+                  `Route: ${res.data.length.toFixed(2)}km, ${res.data.points} points, ${res.data.checkpoints} checkpoints`,
+                );
+                for (const warning of res.data.warnings) {
+                  logger?.warn(`Route: ${warning}`);
+                }
+              } else {
+                logger?.error(`Invalid route: ${res.error.message}`);
+              }
+            })
+            .catch((error) => {
+              logger?.error(`Error checking route: ${error}`);
+            });
+
           const json = JSON.parse(data);
           const geojson = L.geoJSON(json);
           if (isDef(map)) {
@@ -100,6 +162,28 @@ export default function Main() {
     return undefined;
   }
 
+  function previewPayload() {
+    if (!isDef(file)) {
+      logger?.warn("No file selected!");
+      return;
+    }
+
+    file()
+      .text()
+      .then((data) => commands.previewPayload(data, mileage(), time().getTime()))
+      .then(async (res) => {
+        if (res.status === "ok") {
+          await navigator.clipboard.writeText(res.data);
+          logger?.info("Payload copied to clipboard!");
+        } else {
+          logger?.error(`Error building payload: ${res.error.message}`);
+        }
+      })
+      .catch((error) => {
+        logger?.error(`Error building payload: ${error}`);
+      });
+  }
+
   return (
     <TwoColumn
       first={<LeafletMap class="w-full h-full" map={[map, setMap]} />}
@@ -125,11 +209,33 @@ export default function Main() {
                 if (typeof data === "string") {
                   commands
                     .upload(data, mileage(), time().getTime())
-                    .then((res) =>
-                      res.status === "ok"
-                        ? logger?.info("Upload successful!")
-                        : logger?.error(`Error uploading: ${res.error}`),
-                    )
+                    .then((res) => {
+                      if (res.status === "ok") {
+                        const receipt = res.data;
+                        logger?.info(
+                          `Upload successful! Record ${receipt.recordId ?? "unknown"}`,
+                        );
+                        if (!receipt.effective) {
+                          logger?.warn(
+                            `The run does not count: ${receipt.uneffectiveReason ?? "no reason given"}`,
+                          );
+                        } else if (receipt.effectiveMileage !== null) {
+                          logger?.info(
+                            `${receipt.effectiveMileage.toFixed(2)}km counted`,
+                          );
+                        } else if (receipt.mileage.clamped) {
+                          logger?.warn(
+                            `Only ${receipt.mileage.adjusted.toFixed(2)}km counted`,
+                          );
+                        }
+                        refreshLimits();
+                      } else {
+                        logger?.error(`Error uploading: ${res.error.message}`);
+                        if (res.error.code === "relogin_required") {
+                          setLogined(false);
+                        }
+                      }
+                    })
                     .catch((error) => {
                       logger?.error(`Error uploading: ${error}`);
                     })
@@ -161,6 +267,21 @@ export default function Main() {
                 </span>
               </div>
               <Slider value={[percentage, setPercentage]} />
+              <Show when={check()?.clamped && check()}>
+                {(check) => (
+                  <span class="block text-amber-600 text-sm">
+                    Only {check().adjusted.toFixed(2)}km will count
+                  </span>
+                )}
+              </Show>
+              <Show when={limits()}>
+                {(limits) => (
+                  <span class="text-gray-500 text-sm">
+                    {limits().remainingDaily.toFixed(2)}km left today,{" "}
+                    {limits().remainingWeekly.toFixed(2)}km this week
+                  </span>
+                )}
+              </Show>
             </label>
             <Uploader
               file={[file, updateFile]}
@@ -169,6 +290,9 @@ export default function Main() {
             <Button type="submit" disabled={pending()}>
               Upload
             </Button>
+            <Button disabled={pending()} onClick={previewPayload}>
+              Copy Payload
+            </Button>
           </form>
         </div>
       }
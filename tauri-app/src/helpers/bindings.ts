@@ -5,7 +5,7 @@
 
 
 export const commands = {
-async login(username: string, password: string) : Promise<Result<null, string>> {
+async login(username: string, password: string) : Promise<Result<null, CommandError>> {
     try {
     return { status: "ok", data: await TAURI_INVOKE("login", { username, password }) };
 } catch (e) {
@@ -13,7 +13,18 @@ async login(username: string, password: string) : Promise<Result<null, string>>
     else return { status: "error", error: e  as any };
 }
 },
-async getDailyLimit() : Promise<Result<number, string>> {
+/**
+ * Restores the session saved by the last login, returns whether it is still valid.
+ */
+async restoreSession() : Promise<Result<boolean, CommandError>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("restore_session") };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async getDailyLimit() : Promise<Result<number, CommandError>> {
     try {
     return { status: "ok", data: await TAURI_INVOKE("get_daily_limit") };
 } catch (e) {
@@ -21,7 +32,67 @@ async getDailyLimit() : Promise<Result<number, string>> {
     else return { status: "error", error: e  as any };
 }
 },
-async upload(geojson: string, mileage: number, endTime: number) : Promise<Result<null, string>> {
+async getRunningLimits() : Promise<Result<RunningLimits, CommandError>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("get_running_limits") };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async getSemester() : Promise<Result<Semester, CommandError>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("get_semester") };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+/**
+ * Fetches the running limits again, e.g. after an upload.
+ */
+async refreshLimits() : Promise<Result<RunningLimits, CommandError>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("refresh_limits") };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+/**
+ * Checks a mileage against the running limits before it is uploaded.
+ */
+async checkMileage(mileage: number) : Promise<Result<MileageCheck, CommandError>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("check_mileage", { mileage }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+/**
+ * Checks a GeoJSON route before it is uploaded.
+ */
+async routeInfo(geojson: string) : Promise<Result<RouteInfo, CommandError>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("route_info", { geojson }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+/**
+ * Builds the signed payload an upload would send, as pretty-printed JSON.
+ */
+async previewPayload(geojson: string, mileage: number, endTime: number) : Promise<Result<string, CommandError>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("preview_payload", { geojson, mileage, endTime }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async upload(geojson: string, mileage: number, endTime: number) : Promise<Result<UploadReceipt, CommandError>> {
     try {
     return { status: "ok", data: await TAURI_INVOKE("upload", { geojson, mileage, endTime }) };
 } catch (e) {
@@ -41,6 +112,31 @@ async upload(geojson: string, mileage: number, endTime: number) : Promise<Result
 
 /** user-defined types **/
 
+/**
+ * Extent of a route, in WGS-84.
+ */
+export type BoundingBox = { minLongitude: number; minLatitude: number; maxLongitude: number; maxLatitude: number }
+/**
+ * Error forwarded to the frontend, carrying the kind of failure alongside its message.
+ */
+export type CommandError = { code: string; message: string }
+/**
+ * Running limits of the current semester, in kilometers.
+ */
+export type RunningLimits = { daily: number; weekly: number; today: number; thisWeek: number; effectiveStart: number; effectiveEnd: number; remainingDaily: number; remainingWeekly: number }
+/**
+ * How a requested mileage fits the running limits, in kilometers.
+ */
+export type MileageCheck = { requested: number; adjusted: number; clamped: boolean; withinDaily: boolean; withinWeekly: boolean; withinRange: boolean }
+/**
+ * Statistics of a route, lengths in kilometers and distances in meters.
+ */
+export type RouteInfo = { length: number; points: number; boundingBox: BoundingBox; maxSegment: number; closureGap: number; inChina: boolean; duplicatePoints: number[]; checkpoints: number; crs: string; warnings: string[] }
+export type Semester = { id: string; name: string | null }
+/**
+ * What the server recorded for an upload.
+ */
+export type UploadReceipt = { recordId: string | null; effectiveMileage: number | null; effective: boolean; uneffectiveReason: string | null; mileage: MileageCheck }
 
 
 /** tauri-specta globals **/